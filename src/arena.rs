@@ -0,0 +1,264 @@
+//! Node storage for `ATree`. Nodes live in one `Vec` and refer to each other
+//! by `NodeId`, so matching walks plain indices instead of borrowing shared
+//! cells. Slots freed by `ATree::remove` are reused by later inserts.
+//!
+//! The arena only holds structure. What a match learns about each node lives
+//! in an `Evaluation` owned by that call, so the tree isn't touched while
+//! matching.
+
+use crate::collections::HashSet;
+use crate::logic::{kleene_and, kleene_or};
+use crate::LogOperation::{self, And, AtLeast, Not, Or};
+use crate::SubscriptionId;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::{Index, IndexMut};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct NodeId(u32);
+
+#[derive(Debug, Clone)]
+pub(crate) enum SlotKind{
+    Leaf,
+    Inner{ log_operation: LogOperation },
+    Root{
+        log_operation: LogOperation,
+        ids: HashSet<String>,
+        /// Every insert of an expression with this structure, with the
+        /// expression id it was inserted under.
+        subscriptions: Vec<(SubscriptionId, String)>
+    }
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct NodeSlot{
+    pub(crate) id: u64,
+    /// Leaves are level 1 and every other node is one above its highest
+    /// child. Children don't change once a node exists, so this is computed
+    /// on insert.
+    pub(crate) level: u32,
+    pub(crate) kind: SlotKind,
+    pub(crate) children: Vec<NodeId>,
+    /// One entry per link, so a node that is a child twice is listed twice.
+    /// A root that is also part of another expression has parents too.
+    pub(crate) parents: Vec<NodeId>
+}
+
+impl NodeSlot{
+    pub(crate) fn new(id: u64, level: u32, kind: SlotKind, children: Vec<NodeId>) -> Self{
+        Self{
+            id,
+            level,
+            kind,
+            children,
+            parents: vec![]
+        }
+    }
+
+    pub(crate) fn log_operation(&self) -> Option<&LogOperation>{
+        match &self.kind {
+            SlotKind::Leaf => None,
+            SlotKind::Inner{ log_operation } | SlotKind::Root{ log_operation, .. } => Some(log_operation)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Arena{
+    slots: Vec<Option<NodeSlot>>,
+    free: Vec<NodeId>
+}
+
+impl Arena{
+    pub(crate) fn alloc(&mut self, slot: NodeSlot) -> NodeId{
+        match self.free.pop() {
+            Some(id) => {
+                self.slots[id.0 as usize] = Some(slot);
+                id
+            }
+            None => {
+                self.slots.push(Some(slot));
+                NodeId((self.slots.len() - 1) as u32)
+            }
+        }
+    }
+
+    /// Empties the slot and hands back what it held; links to it elsewhere
+    /// are the caller's to drop.
+    pub(crate) fn release(&mut self, id: NodeId) -> Option<NodeSlot>{
+        let slot = self.slots.get_mut(id.0 as usize)?.take()?;
+        self.free.push(id);
+        Some(slot)
+    }
+
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (NodeId, &NodeSlot)>{
+        self.slots.iter().enumerate().filter_map(|(i, slot)| Some((NodeId(i as u32), slot.as_ref()?)))
+    }
+
+    /// The predicate ids of every leaf below `id`.
+    pub(crate) fn collect_leaf_ids(&self, id: NodeId, ids: &mut HashSet<u64>){
+        match self[id].kind {
+            SlotKind::Leaf => {
+                ids.insert(self[id].id);
+            }
+            _ => self[id].children.iter().for_each(|child| self.collect_leaf_ids(*child, ids))
+        }
+    }
+}
+
+impl Index<NodeId> for Arena{
+    type Output = NodeSlot;
+
+    fn index(&self, id: NodeId) -> &NodeSlot {
+        self.slots[id.0 as usize].as_ref().expect("node id of a released slot")
+    }
+}
+
+impl IndexMut<NodeId> for Arena{
+    fn index_mut(&mut self, id: NodeId) -> &mut NodeSlot {
+        self.slots[id.0 as usize].as_mut().expect("node id of a released slot")
+    }
+}
+
+/// The state of one match, with an entry per arena slot: `None` until a leaf
+/// gets its result or an inner node its first operand, then the result so
+/// far. Threshold nodes also count their true and false operands; that table
+/// is only allocated once one of them gets an operand.
+pub(crate) struct Evaluation{
+    results: Vec<Option<Option<bool>>>,
+    tallies: Vec<(u32, u32)>
+}
+
+impl Evaluation{
+    pub(crate) fn new(arena: &Arena) -> Self{
+        Evaluation{
+            results: vec![None; arena.slots.len()],
+            tallies: vec![]
+        }
+    }
+
+    pub(crate) fn get(&self, id: NodeId) -> Option<Option<bool>>{
+        self.results[id.0 as usize]
+    }
+
+    pub(crate) fn set(&mut self, id: NodeId, result: Option<bool>){
+        self.results[id.0 as usize] = Some(result);
+    }
+
+    /// The node's result so far; unknown if nothing reached it.
+    pub(crate) fn result(&self, id: NodeId) -> Option<bool>{
+        self.get(id).flatten()
+    }
+
+    /// Whether further operands can no longer change the result of `node`:
+    /// an AND that saw false, an OR that saw true, a NOT with its operand or
+    /// a threshold that is settled either way.
+    pub(crate) fn decided(&self, id: NodeId, node: &NodeSlot) -> bool{
+        matches!(
+            (self.result(id), node.log_operation()),
+            (Some(false), Some(And)) | (Some(true), Some(Or)) | (Some(_), Some(Not | AtLeast(_)))
+        )
+    }
+
+    /// Combines `operand` into the result of `node` (stored at `id`) and
+    /// returns whether it was the first operand to arrive. Callers skip
+    /// nodes that are already `decided`.
+    pub(crate) fn deliver(&mut self, id: NodeId, node: &NodeSlot, operand: Option<bool>) -> bool{
+        let entry = self.results[id.0 as usize];
+        let result = match (entry, node.log_operation()) {
+            (_, Some(Not)) => operand.map(|value| !value),
+            (_, Some(AtLeast(threshold))) => self.tally(id, *threshold, node.children.len(), operand),
+            (Some(result), Some(And)) => kleene_and(result, operand),
+            (Some(result), Some(Or)) => kleene_or(result, operand),
+            _ => operand
+        };
+        self.results[id.0 as usize] = Some(result);
+        entry.is_none()
+    }
+
+    /// Counts `operand` for a threshold node with `children` children and
+    /// returns its result so far.
+    fn tally(&mut self, id: NodeId, threshold: u32, children: usize, operand: Option<bool>) -> Option<bool>{
+        if self.tallies.is_empty() {
+            self.tallies = vec![(0, 0); self.results.len()];
+        }
+        let (trues, falses) = &mut self.tallies[id.0 as usize];
+        match operand {
+            Some(true) => *trues += 1,
+            Some(false) => *falses += 1,
+            None => {}
+        }
+        if *trues >= threshold {
+            Some(true)
+        } else if *falses as usize + threshold as usize > children {
+            Some(false)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    fn leaf(id: u64) -> NodeSlot{
+        NodeSlot::new(id, 1, SlotKind::Leaf, vec![])
+    }
+
+    #[test]
+    fn released_slots_are_reused(){
+        let mut arena = Arena::default();
+        let first = arena.alloc(leaf(1));
+        let second = arena.alloc(leaf(2));
+
+        assert_eq!(1, arena.release(first).unwrap().id);
+        assert!(arena.release(first).is_none());
+        assert_eq!(vec![second], arena.iter().map(|(id, _)| id).collect::<Vec<_>>());
+
+        assert_eq!(first, arena.alloc(leaf(3)));
+        assert_eq!(3, arena[first].id);
+        assert_eq!(2, arena.iter().count());
+    }
+
+    #[test]
+    fn evaluation_folds_operands_with_the_node_operation(){
+        let mut arena = Arena::default();
+        let and = arena.alloc(NodeSlot::new(1, 2, SlotKind::Inner{ log_operation: And }, vec![]));
+        let or = arena.alloc(NodeSlot::new(2, 2, SlotKind::Inner{ log_operation: Or }, vec![]));
+        let mut evaluation = Evaluation::new(&arena);
+
+        assert_eq!(None, evaluation.get(and));
+        assert!(evaluation.deliver(and, &arena[and], Some(true)));
+        assert!(!evaluation.deliver(and, &arena[and], Some(false)));
+        assert!(!evaluation.deliver(and, &arena[and], Some(true)));
+        assert_eq!(Some(false), evaluation.result(and));
+        assert!(evaluation.decided(and, &arena[and]));
+
+        evaluation.deliver(or, &arena[or], Some(false));
+        assert!(!evaluation.decided(or, &arena[or]));
+        evaluation.deliver(or, &arena[or], Some(true));
+        assert_eq!(Some(true), evaluation.result(or));
+        assert!(evaluation.decided(or, &arena[or]));
+    }
+
+    #[test]
+    fn threshold_counts_operands_until_decided(){
+        let mut arena = Arena::default();
+        let children = (0..4).map(|id| arena.alloc(leaf(id))).collect::<Vec<_>>();
+        let two_of_four = arena.alloc(NodeSlot::new(5, 2, SlotKind::Inner{ log_operation: AtLeast(2) }, children));
+        let deliver = |operands: &[Option<bool>]| {
+            let mut evaluation = Evaluation::new(&arena);
+            for operand in operands {
+                evaluation.deliver(two_of_four, &arena[two_of_four], *operand);
+            }
+            evaluation.result(two_of_four)
+        };
+
+        assert_eq!(Some(true), deliver(&[Some(false), Some(true), None, Some(true)]));
+        assert_eq!(Some(false), deliver(&[Some(false), None, Some(false), Some(false)]));
+        assert_eq!(None, deliver(&[Some(true), Some(false), Some(false), None]));
+        assert_eq!(None, deliver(&[Some(true), None]));
+    }
+}
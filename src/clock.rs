@@ -0,0 +1,70 @@
+//! Time source for deadlines. The tree never reads the system time directly,
+//! so tests can drive it with a `ManualClock` and `no_std` users can plug in
+//! whatever timer their platform has.
+
+use crate::sync::{Lock, ThreadSafe};
+
+pub trait Clock: ThreadSafe{
+    /// Milliseconds since an arbitrary but fixed epoch.
+    fn now_millis(&self) -> i64;
+}
+
+/// Milliseconds since the Unix epoch, from the system time.
+#[cfg(feature = "std")]
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+#[cfg(feature = "std")]
+impl Clock for SystemClock{
+    fn now_millis(&self) -> i64 {
+        match std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH) {
+            Ok(since) => i64::try_from(since.as_millis()).unwrap_or(i64::MAX),
+            Err(before) => i64::try_from(before.duration().as_millis()).map_or(i64::MIN, |millis| -millis)
+        }
+    }
+}
+
+/// A clock that only moves when told to.
+#[derive(Debug, Default)]
+pub struct ManualClock(Lock<i64>);
+
+impl ManualClock{
+    pub fn new(now_millis: i64) -> Self{
+        ManualClock(Lock::new(now_millis))
+    }
+
+    pub fn set(&self, now_millis: i64){
+        self.0.with(|now| *now = now_millis);
+    }
+
+    pub fn advance(&self, millis: i64){
+        self.0.with(|now| *now = now.wrapping_add(millis));
+    }
+}
+
+impl Clock for ManualClock{
+    fn now_millis(&self) -> i64 {
+        self.0.with(|now| *now)
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn manual_clock_only_moves_when_told(){
+        let clock = ManualClock::new(1_000);
+        assert_eq!(1_000, clock.now_millis());
+        clock.advance(250);
+        assert_eq!(1_250, clock.now_millis());
+        clock.set(-5);
+        assert_eq!(-5, clock.now_millis());
+    }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn system_clock_is_past_2020(){
+        assert!(SystemClock.now_millis() > 1_577_836_800_000);
+    }
+}
@@ -0,0 +1,13 @@
+//! Map and set types used by the tree and the predicate store.
+//!
+//! With the `std` feature these are the standard hash collections. Without it
+//! the B-tree collections from `alloc` stand in for them, since the std hashers
+//! aren't available; they share the parts of the API this crate uses.
+
+pub use alloc::collections::VecDeque;
+
+#[cfg(feature = "std")]
+pub use std::collections::{HashMap, HashSet};
+
+#[cfg(not(feature = "std"))]
+pub use alloc::collections::{BTreeMap as HashMap, BTreeSet as HashSet};
@@ -0,0 +1,71 @@
+//! Hasher for predicate ids. It is FNV-1a, so ids are stable across runs and
+//! builds, and it only needs `core`.
+
+use core::hash::Hasher;
+
+const OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const PRIME: u64 = 0x0000_0100_0000_01b3;
+const PAYLOAD_BITS: u32 = 56;
+const PAYLOAD_MASK: u64 = (1 << PAYLOAD_BITS) - 1;
+
+pub struct IdHasher(u64);
+
+impl IdHasher{
+    pub fn new() -> Self{
+        Self(OFFSET_BASIS)
+    }
+}
+
+impl Default for IdHasher{
+    fn default() -> Self{
+        Self::new()
+    }
+}
+
+impl Hasher for IdHasher{
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(PRIME);
+        }
+    }
+}
+
+/// An id whose top byte is `tag` and whose low 56 bits come from `hash`.
+pub fn tagged(tag: u8, hash: u64) -> u64{
+    (u64::from(tag) << PAYLOAD_BITS) | (hash & PAYLOAD_MASK)
+}
+
+/// The top byte of an id built by `tagged`.
+pub fn tag_of(id: u64) -> u8{
+    (id >> PAYLOAD_BITS) as u8
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn matches_reference_fnv1a_values(){
+        let hash = |bytes: &[u8]| {
+            let mut h = IdHasher::new();
+            h.write(bytes);
+            h.finish()
+        };
+        assert_eq!(0xcbf2_9ce4_8422_2325, hash(b""));
+        assert_eq!(0xaf63_dc4c_8601_ec8c, hash(b"a"));
+        assert_eq!(0x8594_4171_f739_67e8, hash(b"foobar"));
+    }
+
+    #[test]
+    fn tag_replaces_the_top_byte(){
+        let id = tagged(0x04, 0x8594_4171_f739_67e8);
+        assert_eq!(0x0494_4171_f739_67e8, id);
+        assert_eq!(0x04, tag_of(id));
+        assert_eq!(0x00, tag_of(tagged(0, u64::MAX)));
+    }
+}
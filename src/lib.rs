@@ -1,31 +1,106 @@
-use std::cell::RefCell;
-use std::collections::{HashMap, HashSet, VecDeque};
-use std::ops::{Add, Deref, DerefMut};
-use std::sync::Arc;
-
-use crate::predicates::{Predicate, Value};
-use crate::LogOperation::{And, Or};
-
-mod predicates;
+//! An A-Tree indexes boolean expressions over predicates and finds the
+//! expressions an event satisfies, sharing common subexpressions between them.
+//!
+//! Predicates are registered with a [`PredicateStore`], which turns an
+//! [`Event`] into [`PredResult`]s. Expressions are built from
+//! [`NodeType`] nodes whose leaves carry predicate ids, inserted into an
+//! [`ATree`], and matched against the results:
+//!
+//! ```
+//! use A_Tree::{add_children, ATree, Event, EventValue, LeafNode, NodeType, PredicateStore, RootNode};
+//! use A_Tree::predicates::{equal, greater, Value};
+//!
+//! let mut store = PredicateStore::new();
+//! let exchange = store.add("exchange".into(), equal(Value::String("X".into()))).unwrap();
+//! let price = store.add("price".into(), greater(Value::Int(5))).unwrap();
+//!
+//! // exchange = "X" AND price > 5
+//! let mut root = NodeType::new_root(RootNode::and("cheap-on-x".into()));
+//! add_children(&mut root, &mut NodeType::new_leaf(LeafNode::new(exchange)));
+//! add_children(&mut root, &mut NodeType::new_leaf(LeafNode::new(price)));
+//!
+//! let mut tree = ATree::new();
+//! tree.insert(root).unwrap();
+//!
+//! let event = Event::new(vec![
+//!     EventValue{ name: "exchange".into(), value: Value::String("X".into()) },
+//!     EventValue{ name: "price".into(), value: Value::Int(10) },
+//! ]);
+//! let matched = tree.matches(&store.evaluate(&event));
+//! assert!(matched.contains("cheap-on-x"));
+//! ```
+
+#![forbid(unsafe_code)]
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+
+use alloc::rc::Rc;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::cell::RefCell;
+use core::fmt;
+use core::ops::Deref;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use core::hash::{Hash, Hasher};
+
+use crate::arena::{Arena, Evaluation, NodeId, NodeSlot, SlotKind};
+use crate::clock::Clock;
+use crate::collections::{HashMap, HashSet, VecDeque};
+use crate::hash::{tag_of, tagged, IdHasher};
+use crate::logic::kleene_or;
+use crate::pattern::AttributePattern;
+use crate::predicates::{CrossAttrPredicate, Predicate, PredicateKind, Value};
+use crate::sync::Lock;
+use crate::warning::{Warning, WarningSink};
+use crate::wire::DecodeError;
+use crate::LogOperation::{And, AtLeast, Not, Or};
+
+mod arena;
+pub mod clock;
+mod collections;
+mod hash;
+mod logic;
+mod pattern;
+pub mod postfix;
+pub mod predicates;
+mod sync;
+#[cfg(test)]
+mod test_rng;
+pub mod warning;
+pub mod wire;
 
+/// The nodes expressions are built from. They only describe structure;
+/// `ATree::insert` copies them into the tree's arena, and matching keeps its
+/// per-event state in each call.
 #[derive(Debug, Clone)]
-enum NodeType {
+pub enum NodeType {
     LeafNodeType(LeafNode),
     InnerNodeType(InnerNode),
     RootNodeType(RootNode)
 }
 
 impl NodeType{
-    fn new_leaf(node: LeafNode) -> ArcNodeLink{
-        Arc::new(RefCell::new(NodeType::LeafNodeType(node)))
+    pub fn new_leaf(node: LeafNode) -> ArcNodeLink{
+        Rc::new(RefCell::new(NodeType::LeafNodeType(node)))
+    }
+
+    pub fn new_inner(node: InnerNode) -> ArcNodeLink{
+        Rc::new(RefCell::new(NodeType::InnerNodeType(node)))
     }
 
-    fn new_inner(node: InnerNode) -> ArcNodeLink{
-        Arc::new(RefCell::new(NodeType::InnerNodeType(node)))
+    pub fn new_root(node: RootNode) -> ArcNodeLink{
+        Rc::new(RefCell::new(NodeType::RootNodeType(node)))
     }
 
-    fn new_root(node: RootNode) -> ArcNodeLink{
-        Arc::new(RefCell::new(NodeType::RootNodeType(node)))
+    /// Leaves are level 1 and every other node is one above its highest
+    /// child, so a root's level is the depth of its expression.
+    pub fn get_level(&self) -> u32 {
+        let children = self.get_children().unwrap_or_default();
+        children.iter().map(|node| node.borrow().get_level()).max().unwrap_or(0) + 1
     }
 }
 
@@ -41,15 +116,7 @@ impl Node for NodeType{
         }
     }
 
-    fn get_level(&self, level: u32) -> u32 {
-        match self {
-            NodeType::LeafNodeType(node) => {node.get_level(level)}
-            NodeType::InnerNodeType(node) => {node.get_level(level)}
-            NodeType::RootNodeType(node) => {node.get_level(level)}
-        }
-    }
-
-    fn add_children(&mut self, node: Arc<RefCell<Self::Node>>) -> Option<Arc<RefCell<Self::Node>>> {
+    fn add_children(&mut self, node: Rc<RefCell<Self::Node>>) -> Option<Rc<RefCell<Self::Node>>> {
         match self {
             NodeType::LeafNodeType(n) => { n.add_children(node)}
             NodeType::InnerNodeType(n) => { n.add_children(node)}
@@ -58,50 +125,67 @@ impl Node for NodeType{
     }
 
 
-    fn get_children(&self) -> Option<&[Arc<RefCell<Self::Node>>]>{
+    fn get_children(&self) -> Option<&[Rc<RefCell<Self::Node>>]>{
         match self {
             NodeType::LeafNodeType(node) => {node.get_children()}
             NodeType::InnerNodeType(node) => {node.get_children()}
             NodeType::RootNodeType(node) => {node.get_children()}
         }
     }
+}
 
-    fn add_parent(&mut self, node: Arc<RefCell<Self::Node>>) -> Option<Arc<RefCell<Self::Node>>> {
-        match self {
-            NodeType::LeafNodeType(n) => { n.add_parent(node)}
-            NodeType::InnerNodeType(n) => { n.add_parent(node)}
-            NodeType::RootNodeType(n) => { n.add_parent(node)}
-        }
-    }
+#[derive(Debug,Clone)]
+pub enum LogOperation{
+    And,Or,
+    /// Negates its only child; unknown stays unknown.
+    Not,
+    /// True once at least this many children are true, false once too many
+    /// are false for that to happen, unknown in between. A threshold above
+    /// the number of children never matches.
+    AtLeast(u32)
+}
 
-    fn get_parents(&self) -> Option<&[Arc<RefCell<Self::Node>>]> {
-        match self {
-            NodeType::LeafNodeType(node) => {node.get_parents()}
-            NodeType::InnerNodeType(node) => {node.get_parents()}
-            NodeType::RootNodeType(node) => {node.get_parents()}
-        }
+/// The id of an inner or root node, which is what `ATree::insert` dedupes
+/// nodes by.
+fn operation_id(log_operation: &LogOperation, childrens: &[ArcNodeLink]) -> u64{
+    match (log_operation, childrens) {
+        (And | Or, [child]) => single_child_id(child),
+        (And, _) => childrens.iter().fold(0, |a, b|{a.overflowing_add(b.borrow().get_id()).0}),
+        (Or, _) => childrens.iter().fold(1, |a, b|{a.overflowing_mul(b.borrow().get_id()).0}),
+        (Not, _) => negated_id(childrens),
+        (AtLeast(threshold), _) => threshold_id(*threshold, childrens)
     }
+}
 
-    fn evaluate(&self) -> Option<bool> {
-        match self {
-            NodeType::LeafNodeType(node) => {node.evaluate()}
-            NodeType::InnerNodeType(node) => {node.evaluate()}
-            NodeType::RootNodeType(node) => {node.evaluate()}
-        }
-    }
+/// A sum or product over one child is the child's own id, so an AND or OR
+/// with a single child would take over its child's entry on insert. They
+/// hash the child's id instead; over one child AND and OR are the same node.
+fn single_child_id(child: &ArcNodeLink) -> u64{
+    let mut h = IdHasher::new();
+    h.write_u8(b'(');
+    h.write_u64(child.borrow().get_id());
+    h.finish()
+}
 
-    fn clean(&mut self) {
-        match self {
-            NodeType::LeafNodeType(node) => {node.clean()}
-            NodeType::InnerNodeType(node) => {node.clean()}
-            NodeType::RootNodeType(node) => {node.clean()}
-        }
+/// A NOT node hashes its child's id, so it shares an id neither with an AND
+/// or OR over the same child nor with the child itself.
+fn negated_id(childrens: &[ArcNodeLink]) -> u64{
+    let mut h = IdHasher::new();
+    h.write_u8(b'!');
+    for children in childrens {
+        h.write_u64(children.borrow().get_id());
     }
+    h.finish()
 }
 
-#[derive(Debug,Clone)]
-enum LogOperation{
-    And,Or
+/// A threshold node mixes its threshold into the sum of its children's ids,
+/// so `AtLeast(2)` and `AtLeast(3)` over the same children stay apart.
+fn threshold_id(threshold: u32, childrens: &[ArcNodeLink]) -> u64{
+    let mut h = IdHasher::new();
+    h.write_u8(b'#');
+    h.write_u32(threshold);
+    h.write_u64(childrens.iter().fold(0, |a, b| a.wrapping_add(b.borrow().get_id())));
+    h.finish()
 }
 
 
@@ -110,34 +194,25 @@ trait Node{
     type Node;
 
     fn get_id(&self) -> u64;
-    fn get_level(&self, level:u32) -> u32;
-
-    fn add_children(&mut self, node: Arc<RefCell<Self::Node>>) -> Option<Arc<RefCell<Self::Node>>>;
-    fn get_children(&self) -> Option<&[Arc<RefCell<Self::Node>>]>;
 
-    fn add_parent(&mut self, node: Arc<RefCell<Self::Node>>) -> Option<Arc<RefCell<Self::Node>>>;
-    fn get_parents(&self) -> Option<&[Arc<RefCell<Self::Node>>]>;
-
-    fn evaluate(&self) -> Option<bool>;
-    fn clean(&mut self);
+    fn add_children(&mut self, node: Rc<RefCell<Self::Node>>) -> Option<Rc<RefCell<Self::Node>>>;
+    fn get_children(&self) -> Option<&[Rc<RefCell<Self::Node>>]>;
 
 }
 
-type ArcNodeLink =  Arc<RefCell<NodeType>>;
+/// Handle to a node while an expression is built. Only used on the building
+/// thread; the tree keeps copies, so it is an `Rc` despite the name.
+pub type ArcNodeLink =  Rc<RefCell<NodeType>>;
 
 #[derive(Debug, Clone)]
-struct LeafNode{
-    predicate_id: u64,
-    parents: Vec<ArcNodeLink>,
-    pub result: Option<bool>
+pub struct LeafNode{
+    predicate_id: u64
 }
 
 impl LeafNode{
-    fn new(predicate_id: u64) -> Self{
+    pub fn new(predicate_id: u64) -> Self{
         Self{
-            predicate_id,
-            parents: vec![],
-            result: None
+            predicate_id
         }
     }
 }
@@ -151,232 +226,115 @@ impl Node for LeafNode{
         self.predicate_id
     }
 
-    fn get_level(&self, level: u32) -> u32 {
-        level.add(1)
-    }
-
-    fn add_children(&mut self, _: Arc<RefCell<Self::Node>>) -> Option<Arc<RefCell<Self::Node>>> {
+    fn add_children(&mut self, _: Rc<RefCell<Self::Node>>) -> Option<Rc<RefCell<Self::Node>>> {
         None
     }
 
-    fn get_children(&self) -> Option<&[Arc<RefCell<Self::Node>>]> {
+    fn get_children(&self) -> Option<&[Rc<RefCell<Self::Node>>]> {
         None
     }
-
-    fn add_parent(&mut self, node: Arc<RefCell<Self::Node>>) -> Option<Arc<RefCell<Self::Node>>>{
-        let r = node.clone();
-        self.parents.push(node);
-        Some(r)
-    }
-
-    fn get_parents(&self) -> Option<&[Arc<RefCell<Self::Node>>]> {
-        Some(self.parents.as_slice())
-    }
-
-    fn evaluate(&self) -> Option<bool> {
-        self.result
-    }
-
-    fn clean(&mut self) {
-        self.result = None
-    }
 }
 
 #[derive(Debug, Clone)]
-struct InnerNode{
+pub struct InnerNode{
     pub log_operation: LogOperation,
-    parents: Vec<ArcNodeLink>,
-    childrens: Vec<ArcNodeLink>,
-    pub operands: Vec<Option<bool>>
+    childrens: Vec<ArcNodeLink>
 }
 
 impl InnerNode{
-    fn new(log_operation: LogOperation) -> Self{
+    pub fn new(log_operation: LogOperation) -> Self{
         Self{
             log_operation,
-            parents: vec![],
-            childrens: vec![],
-            operands: vec![]
+            childrens: vec![]
         }
     }
 
-    fn and() -> Self {
+    pub fn and() -> Self {
         Self{
             log_operation: And,
-            parents: vec![],
-            childrens: vec![],
-            operands: vec![]
+            childrens: vec![]
         }
     }
 
-    fn or() -> Self {
+    pub fn or() -> Self {
         Self{
             log_operation: Or,
-            parents: vec![],
-            childrens: vec![],
-            operands: vec![]
+            childrens: vec![]
         }
     }
-}
-
-impl Node for InnerNode{
 
-    type Node = NodeType;
-    fn get_id(&self) -> u64 {
-        match self.log_operation {
-            LogOperation::And => {
-                self.childrens.iter().fold(0, |a, b|{a.overflowing_add(b.borrow().get_id()).0})
-            }
-            LogOperation::Or => {self.childrens.iter().fold(1, |a, b|{a.overflowing_mul(b.borrow().get_id()).0})}
+    /// Takes exactly one child; `ATree::insert` rejects anything else.
+    pub fn not() -> Self {
+        Self{
+            log_operation: Not,
+            childrens: vec![]
         }
     }
 
-    fn get_level(&self, level: u32) -> u32 {
-        let mut max_level = 0;
-        for node in &self.childrens {
-            let level = node.borrow().get_level(level + 1);
-            max_level = level.max(max_level);
+    pub fn at_least(threshold: u32) -> Self {
+        Self{
+            log_operation: AtLeast(threshold),
+            childrens: vec![]
         }
-        max_level
-    }
-
-    fn add_children(&mut self, node: Arc<RefCell<Self::Node>>) -> Option<Arc<RefCell<Self::Node>>> {
-        let r = node.clone();
-        self.childrens.push(node);
-        Some(r)
     }
+}
 
+impl Node for InnerNode{
 
-    fn get_children(&self) -> Option<&[Arc<RefCell<Self::Node>>]> {
-        Some(self.childrens.as_slice())
+    type Node = NodeType;
+    fn get_id(&self) -> u64 {
+        operation_id(&self.log_operation, &self.childrens)
     }
 
-    fn add_parent(&mut self, node: Arc<RefCell<Self::Node>>) -> Option<Arc<RefCell<Self::Node>>>{
+    fn add_children(&mut self, node: Rc<RefCell<Self::Node>>) -> Option<Rc<RefCell<Self::Node>>> {
         let r = node.clone();
-        self.parents.push(node);
+        self.childrens.push(node);
         Some(r)
     }
 
-    fn get_parents(&self) -> Option<&[Arc<RefCell<Self::Node>>]> {
-        Some(self.parents.as_slice())
-    }
-
-    fn evaluate(&self) -> Option<bool> {
-        match self.log_operation {
-            And => {
-                let mut iter = self.operands.iter();
-                let mut op1 = iter.next().unwrap().clone();
-                while let Some(op2) = iter.next(){
-                    match (op1, op2.clone()) {
-                        (None, Some(true)) => {op1 = None}
-                        (None, Some(false)) => {op1 = Some(false)}
-                        (Some(true), None) => {op1 = None}
-                        (Some(false), None) => {op1 = Some(false)}
-                        (Some(val1), Some(val2)) => {op1 = Some(val1 && val2)}
-                        (None, None) => { op1 = None }
-                    }
-                }
-                op1
-            }
-            Or => {
-                let mut iter = self.operands.iter();
-                let mut op1 = iter.next().unwrap().clone();
-                while let Some(op2) = iter.next(){
-                    match (op1, op2.clone()) {
-                        (None, Some(true)) => {op1 = Some(true)}
-                        (None, Some(false)) => {op1 = None}
-                        (Some(true), None) => {op1 = Some(true)}
-                        (Some(false), None) => {op1 = None}
-                        (Some(val1), Some(val2)) => {op1 = Some(val1 || val2)}
-                        (None, None) => { op1 = None }
-                    }
-                }
-                op1
-            }
-        }
-
-    }
 
-    fn clean(&mut self) {
-        self.operands.clear()
+    fn get_children(&self) -> Option<&[Rc<RefCell<Self::Node>>]> {
+        Some(self.childrens.as_slice())
     }
 }
 
 #[derive(Debug,Clone)]
-struct RootNode{
+pub struct RootNode{
     childrens: Vec<ArcNodeLink>,
     pub log_operation: LogOperation,
-    pub operands: Vec<Option<bool>>,
     pub ids: HashSet<String>,
-    pub id: String,
-}
-
-struct RootNodeBuilder{
-    node: ArcNodeLink
-}
-
-impl RootNodeBuilder{
-
-
-    fn and(id: String) -> Self{
-        Self{
-            node: Arc::new(RefCell::new(NodeType::RootNodeType(RootNode::new(id, And))))
-        }
-    }
-
-    fn or(id: String,) -> Self{
-        Self{
-            node: Arc::new(RefCell::new(NodeType::RootNodeType(RootNode::new(id, Or))))
-        }
-    }
-
-    fn with_inner_node(&mut self, node: InnerNode) -> &mut Self{
-        let mut node = node;
-        node.add_parent(self.node.clone());
-        self.node.borrow_mut().add_children(Arc::new(RefCell::new(NodeType::InnerNodeType(node))));
-        self
-    }
-
-    fn with_leaf_node(&mut self, node: LeafNode) -> &mut Self{
-        let mut node = node;
-        node.add_parent(self.node.clone());
-        self.node.borrow_mut().add_children(Arc::new(RefCell::new(NodeType::LeafNodeType(node))));
-        self
-    }
+    pub id: String
 }
 
 impl RootNode{
-    fn new(id: String, log_operation: LogOperation) -> Self{
+    pub fn new(id: String, log_operation: LogOperation) -> Self{
         let mut ids = HashSet::new();
         ids.insert(id.clone());
         Self{
             log_operation,
             childrens: vec![],
-            operands: vec![],
             ids,
             id
         }
     }
 
-    fn and(id: String) -> Self {
+    pub fn and(id: String) -> Self {
         let mut ids = HashSet::new();
         ids.insert(id.clone());
         Self{
             log_operation: And,
             childrens: vec![],
-            operands: vec![],
             ids,
-            id,
+            id
         }
     }
 
-    fn or(id: String) -> Self {
+    pub fn or(id: String) -> Self {
         let mut ids = HashSet::new();
         ids.insert(id.clone());
         Self{
             log_operation: Or,
             childrens: vec![],
-            operands: vec![],
             ids,
             id
         }
@@ -390,482 +348,2789 @@ impl Node for RootNode{
 
 
     fn get_id(&self) -> u64 {
-        match self.log_operation {
-            LogOperation::And => {
-                self.childrens.iter().fold(0, |a, b|{a.overflowing_add(b.borrow().get_id()).0})
-            }
-            LogOperation::Or => {
-                self.childrens.iter().fold(1, |a, b|{a.overflowing_mul(b.borrow().get_id()).0})
-            }
-        }
-    }
-
-    fn get_level(&self, level: u32) -> u32 {
-        let mut max_level = 0;
-        for node in &self.childrens {
-            let level = node.borrow().get_level(level + 1);
-            max_level = level.max(max_level);
-        }
-        max_level
+        operation_id(&self.log_operation, &self.childrens)
     }
 
-    fn add_children(&mut self, node: Arc<RefCell<Self::Node>>) -> Option<Arc<RefCell<Self::Node>>> {
+    fn add_children(&mut self, node: Rc<RefCell<Self::Node>>) -> Option<Rc<RefCell<Self::Node>>> {
         let r = node.clone();
         self.childrens.push(node);
         Some(r)
     }
 
-    fn get_children(&self) -> Option<&[Arc<RefCell<Self::Node>>]> {
+    fn get_children(&self) -> Option<&[Rc<RefCell<Self::Node>>]> {
         Some(&self.childrens)
     }
-
-    fn add_parent(&mut self, _: Arc<RefCell<Self::Node>>) -> Option<Arc<RefCell<Self::Node>>>{
-        None
-    }
-
-    fn get_parents(&self) -> Option<&[Arc<RefCell<Self::Node>>]> {
-        None
-    }
-
-    fn evaluate(&self) -> Option<bool> {
-        match self.log_operation {
-            And => {
-                let mut iter = self.operands.iter();
-                let mut op1 = iter.next().unwrap().clone();
-                while let Some(op2) = iter.next(){
-                    match (op1, op2.clone()) {
-                        (None, Some(true)) => {op1 = None}
-                        (None, Some(false)) => {op1 = Some(false)}
-                        (Some(true), None) => {op1 = None}
-                        (Some(false), None) => {op1 = Some(false)}
-                        (Some(val1), Some(val2)) => {op1 = Some(val1 && val2)}
-                        (None, None) => { op1 = None }
-                    }
-                }
-                op1
-            }
-            Or => {
-                let mut iter = self.operands.iter();
-                let mut op1 = iter.next().unwrap().clone();
-                while let Some(op2) = iter.next(){
-                    match (op1, op2.clone()) {
-                        (None, Some(true)) => {op1 = Some(true)}
-                        (None, Some(false)) => {op1 = None}
-                        (Some(true), None) => {op1 = Some(true)}
-                        (Some(false), None) => {op1 = None}
-                        (Some(val1), Some(val2)) => {op1 = Some(val1 || val2)}
-                        (None, None) => { op1 = None }
-                    }
-                }
-                op1
-            }
-        }
-    }
-
-    fn clean(&mut self) {
-        self.operands.clear()
-    }
 }
 
 
-fn add_children(node: &mut ArcNodeLink, children: &mut ArcNodeLink){
-    children.borrow_mut().add_parent(node.deref().clone());
+/// Links `children` under `node`. This is how expressions are built before
+/// they are inserted.
+pub fn add_children(node: &mut ArcNodeLink, children: &mut ArcNodeLink){
     node.borrow_mut().add_children(children.deref().clone());
 }
 
-struct PredResult{
+/// The result of one predicate for an event; `None` means unknown.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PredResult{
     pub id: u64,
     pub result: Option<bool>
 }
 
+impl PredResult{
+    pub fn new(id: u64, result: Option<bool>) -> Self{
+        Self{ id, result }
+    }
+}
 
-struct ATree{
-
-    hash_to_node: HashMap<u64, ArcNodeLink>
 
+#[derive(Debug, PartialEq)]
+pub enum ATreeError{
+    CycleDetected { node_id: u64 },
+    MissingPredicate { predicate_id: u64 },
+    /// A NOT node has `children` children instead of one. `node_id` is its
+    /// position in depth-first order, as for `CycleDetected`.
+    NotArity { node_id: u64, children: usize }
 }
 
-impl ATree{
+#[derive(Debug, Default, PartialEq)]
+pub struct MatchOutcome{
+    pub event_id: u64,
+    pub matched: HashSet<String>,
+    pub resolved_false: HashSet<String>,
+    pub truncated: bool,
+    /// The match was stopped through `MatchOptions::cancel_token`.
+    pub cancelled: bool,
+    /// Predicate results that were unknown and therefore not applied.
+    pub skipped_unknowns: usize,
+    /// Predicate results for ids no expression in the tree uses, i.e. work
+    /// the predicate store could have skipped.
+    pub unreferenced_results: usize,
+    /// More ids matched than `MatchOptions::max_results` allowed.
+    pub overflowed: bool,
+    /// Every matched id seen, including those dropped by the result limit.
+    /// With `stop_on_limit` counting stops at the first one over the limit.
+    pub total_matched: usize,
+    /// Per-level counts, lowest level first, if `collect_level_stats` was set.
+    pub level_breakdown: Vec<LevelStats>,
+    /// Subscriptions of the ids in `matched`, in ascending order.
+    pub subscriptions: Vec<SubscriptionId>,
+    /// Subscriptions of the ids in `resolved_false`, in ascending order.
+    pub resolved_false_subscriptions: Vec<SubscriptionId>
+}
 
-    fn new() -> Self{
-        ATree{
-            hash_to_node: HashMap::new()
-        }
-    }
+/// Handed out by `ATree::insert`, one per insert and never reused by a tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct SubscriptionId(u64);
 
-    fn len(&self) -> usize{
-        self.hash_to_node.len()
+impl SubscriptionId{
+    pub fn value(&self) -> u64{
+        self.0
     }
+}
 
-    pub fn insert(&mut self, node: ArcNodeLink) -> ArcNodeLink{
-        let id = node.borrow().get_id();
-        if let Some(node) = self.hash_to_node.get(&id) {
-            match (node.borrow().deref(), node.borrow_mut().deref_mut()) {
-                (NodeType::RootNodeType(n1), NodeType::RootNodeType(n2)) => {
-                    n2.ids.insert(n1.id.clone());
-                }
-                _ => {}
-            }
-
-            node.clone()
-        }else{
-            let mut child_nodes = vec![];
-            if let Some(childrens) =  node.borrow_mut().get_children(){
-                for children in childrens {
-                    let child_node = self.insert(children.clone());
-                    child_nodes.push(child_node);
-                }
-            }
-
-            let new_node: ArcNodeLink = self.create_new_node(&node, child_nodes.as_mut_slice());
-            self.hash_to_node.insert(new_node.borrow().get_id(), new_node.clone());
+/// Shared flag for aborting a match in flight, e.g. from the thread that
+/// handles the upstream request. Clones refer to the same flag.
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
 
-            new_node
-        }
+impl CancelToken{
+    pub fn new() -> Self{
+        Self::default()
     }
 
-    pub fn get_m(&self) -> u32{
-        let mut max = 0;
-        for x in &self.hash_to_node {
-            let m = x.1.borrow().get_level(0);
-            max = m.max(max)
-        }
-        max
+    pub fn cancel(&self){
+        self.0.store(true, Ordering::Relaxed);
     }
 
-    pub fn matches(&mut self, predicates: &[PredResult]) -> HashSet<String> {
-        let mut queues: HashMap<u32, VecDeque<ArcNodeLink>> = HashMap::new();
-        let mut matching_ids = HashSet::new();
-        let m = self.get_m()+1;
-        for i in 1..m {
-            queues.insert(i, VecDeque::new());
-        }
-        for predicate in predicates {
-            if let  Some(ref mut node) = self.hash_to_node.get(&predicate.id){
-                if let NodeType::LeafNodeType(ref mut node) = node.borrow_mut().deref_mut() {
-                    node.result = predicate.result;
-                }
-                queues.get_mut(&1).unwrap().push_front(node.clone());
-            }
-        }
+    pub fn is_cancelled(&self) -> bool{
+        self.0.load(Ordering::Relaxed)
+    }
+}
 
-        for x in 1..m {
-            while let Some(node) = queues.get_mut(&x).unwrap().pop_front() {
-                let result = node.borrow().evaluate();
-                node.borrow_mut().clean();
+/// What happened at one level during a match. `deliveries` counts results
+/// passed up from this level's nodes to their parents. `short_circuited`
+/// counts nodes skipped because every parent was already decided; they are
+/// not part of `evaluated`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct LevelStats{
+    pub level: u32,
+    pub enqueued: usize,
+    pub evaluated: usize,
+    pub resolved_true: usize,
+    pub resolved_false: usize,
+    pub unknown: usize,
+    pub deliveries: usize,
+    pub short_circuited: usize
+}
 
-                if let None = result {
-                    continue;
-                }
+impl fmt::Display for LevelStats{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "level {}: enqueued {}, evaluated {}, true {}, false {}, unknown {}, deliveries {}, short-circuited {}",
+            self.level, self.enqueued, self.evaluated, self.resolved_true, self.resolved_false, self.unknown, self.deliveries,
+            self.short_circuited
+        )
+    }
+}
 
-                if let Some(parents) = node.borrow().get_parents(){
-                    for parent in parents {
+/// Per-call matching options. `MatchOptions::default()` is what `matches`
+/// uses; options are set through the builder methods so new ones can be
+/// added without breaking callers.
+#[derive(Debug, Clone, Default)]
+pub struct MatchOptions{
+    deadline: Option<i64>,
+    sample_nonce: Option<u64>,
+    strict: bool,
+    event_id: Option<u64>,
+    max_results: Option<usize>,
+    stop_on_limit: bool,
+    collect_level_stats: bool,
+    cancel_token: Option<CancelToken>
+}
 
-                        let level = parent.borrow().get_level(0);
+impl MatchOptions{
+    /// Stops propagating once the tree's clock reaches `deadline` (in the
+    /// clock's milliseconds); see `ATree::matches_until`.
+    pub fn deadline(mut self, deadline: i64) -> Self{
+        self.deadline = Some(deadline);
+        self
+    }
 
-                        match parent.borrow_mut().deref_mut() {
-                            NodeType::InnerNodeType(p) => {
-                                if p.operands.is_empty() {
-                                    let queue = queues.get_mut(&level).unwrap();
-                                    queue.push_front(parent.clone());
-                                }
-                                p.operands.push(result);
-                            }
-                            NodeType::RootNodeType(p) => {
-                                if p.operands.is_empty() {
-                                    let level = p.get_level(0);
-                                    queues.get_mut(&level).unwrap().push_front(parent.clone());
-                                }
-                                p.operands.push(result);
-                            }
-                            _ => {}
-                        }
-                    }
+    /// Applies the tree's sample rates with this nonce; see `ATree::matches_sampled`.
+    pub fn sample_nonce(mut self, nonce: u64) -> Self{
+        self.sample_nonce = Some(nonce);
+        self
+    }
 
-                }
+    /// Uses `event_id` for this call instead of the tree's next sequence number.
+    /// Generated ids continue after it, up to `u64::MAX`: once that has been
+    /// used, every later generated id is `u64::MAX` as well.
+    pub fn event_id(mut self, event_id: u64) -> Self{
+        self.event_id = Some(event_id);
+        self
+    }
 
-                if let Some(true) = result{
+    /// Keeps at most `max_results` matched ids and sets `overflowed` when more
+    /// expressions matched. Which ones are kept follows processing order.
+    pub fn max_results(mut self, max_results: usize) -> Self{
+        self.max_results = Some(max_results);
+        self
+    }
 
-                    match node.borrow().deref() {
-                        NodeType::RootNodeType(n) => {
-                            for id in &n.ids {
-                                matching_ids.insert(id.clone());
-                            }
-                        }
-                        _ => {}
-                    }
+    /// With a result limit, stops propagating as soon as it is exceeded
+    /// instead of finishing the event to count every match.
+    pub fn stop_on_limit(mut self, stop_on_limit: bool) -> Self{
+        self.stop_on_limit = stop_on_limit;
+        self
+    }
 
-                }
-            }
-        }
+    /// Polls `token` with the deadline and stops with `cancelled` set once it
+    /// has been cancelled.
+    pub fn cancel_token(mut self, token: CancelToken) -> Self{
+        self.cancel_token = Some(token);
+        self
+    }
 
-        matching_ids
+    /// Fills `MatchOutcome::level_breakdown`. Off by default so the normal
+    /// path doesn't pay for the counting.
+    pub fn collect_level_stats(mut self, collect_level_stats: bool) -> Self{
+        self.collect_level_stats = collect_level_stats;
+        self
     }
 
-    fn create_new_node(&mut self, node: &ArcNodeLink, child_nodes: &mut [ArcNodeLink]) -> ArcNodeLink{
-        let binding = node.borrow();
-        let new_node = binding.deref();
-        match new_node {
-            NodeType::LeafNodeType(_) => {
-                let mut leaf = NodeType::new_leaf(LeafNode::new(new_node.get_id()));
-                for node in child_nodes {
-                    add_children(&mut leaf, node)
-                }
-                leaf
-            }
-            NodeType::InnerNodeType(n) => {
-                let mut inner = NodeType::new_inner(InnerNode::new(n.log_operation.clone()));
-                for mut node in child_nodes {
-                    add_children(&mut inner, &mut node)
-                }
-                inner
-            }
-            NodeType::RootNodeType(n) => {
-                let mut root = NodeType::new_root(RootNode::new(n.id.clone(), n.log_operation.clone()));
-                for mut node in child_nodes {
-                    add_children(&mut root, &mut node)
-                }
-                root
-            }
-        }
+    /// Treats unknown predicate results as false instead of propagating
+    /// three-valued unknowns.
+    pub fn strict(mut self, strict: bool) -> Self{
+        self.strict = strict;
+        self
     }
 }
 
-struct EventValue{
-    pub name: String,
-    pub value: Value
+#[derive(Debug, Default, PartialEq)]
+pub struct ExpressionPlan{
+    pub would_create_nodes: usize,
+    pub would_share_nodes: usize,
+    pub validation_issues: Vec<ATreeError>
 }
 
-struct Event{
-    values: Vec<EventValue>
+#[derive(Debug, Default, PartialEq)]
+pub struct InsertPlan{
+    pub expressions: Vec<ExpressionPlan>,
+    pub would_create_nodes: usize,
+    pub would_share_nodes: usize
 }
 
+/// Wiring problems between a tree and a predicate store, sorted.
+#[derive(Debug, Default, PartialEq)]
+pub struct AuditReport{
+    /// Predicates that are evaluated for every event but feed no expression.
+    pub predicates_without_expressions: Vec<u64>,
+    /// Expressions that reference a predicate the store doesn't have.
+    pub expressions_with_missing_predicates: Vec<String>
+}
 
-struct PredicateStore{
-    predicates: HashMap<String, Vec<Box<dyn Predicate>>>
+/// Ids handed to match calls; see `ATree::assign_event_id`.
+#[derive(Debug, Default, Clone)]
+struct EventIds{
+    next: u64,
+    last: Option<u64>
 }
 
+/// Expressions are built from `ArcNodeLink`s and copied into the tree's own
+/// node arena on insert, so the tree shares nothing with the nodes passed in
+/// and a clone is independent of the original.
+///
+/// Matching only reads the tree, and with the `std` feature `ATree` is
+/// `Send + Sync`: one tree behind an `Arc` can serve matches from several
+/// threads at once.
+#[derive(Clone)]
+pub struct ATree{
+
+    nodes: Arena,
+    hash_to_node: HashMap<u64, NodeId>,
+    next_subscription_id: u64,
+    deadline_check_interval: usize,
+    sample_rates: HashMap<String, f32>,
+    clock: Option<Arc<dyn Clock>>,
+    event_ids: Lock<EventIds>,
+    warning_sink: Option<Arc<dyn WarningSink>>
 
-impl PredicateStore {
+}
 
-    fn new() -> Self{
-        Self{
-            predicates: HashMap::new()
-        }
+impl Default for ATree{
+    fn default() -> Self {
+        Self::new()
     }
+}
 
-    fn add(&mut self, attribute: String, p: impl Predicate + 'static) -> u64 {
-        let predicates = self.predicates.entry(attribute).or_default();
-        let id = p.id();
-        predicates.push(Box::new(p));
-        id
-    }
+impl ATree{
 
-    fn evaluate(&self, event: &Event) -> Vec<PredResult> {
-        let mut result = vec![];
-        for x in &self.predicates {
-            let event = event.values.iter().find(|&f| { f.name.eq(x.0) });
-            if let Some(event) = event {
-                for predicate in x.1 {
-                    result.push(PredResult{
-                        id: predicate.id(),
-                        result: Some(predicate.evaluate(&event.value))
-                    })
-                }
-            }
+    pub fn new() -> Self{
+        ATree{
+            nodes: Arena::default(),
+            hash_to_node: HashMap::new(),
+            next_subscription_id: 0,
+            deadline_check_interval: 64,
+            sample_rates: HashMap::new(),
+            #[cfg(feature = "std")]
+            clock: Some(Arc::new(clock::SystemClock)),
+            #[cfg(not(feature = "std"))]
+            clock: None,
+            event_ids: Lock::default(),
+            warning_sink: None
         }
-        result
     }
-}
-
-#[cfg(test)]
-mod tests{
-    use super::*;
-    use crate::predicates::Value::Int;
-    use std::collections::HashSet;
-
-    #[test]
-    fn calculate_level_for_three_nodes(){
-        let mut leaf = NodeType::new_leaf(LeafNode::new(1));
-
-        let mut inner = NodeType::new_inner(InnerNode::and());
-        add_children(&mut inner, &mut leaf);
-
-        let mut root = NodeType::new_root(RootNode::and("1".to_string()));
-        add_children(&mut root, &mut inner);
-
 
-        assert_eq!(root.borrow().get_level(0), 3);
+    /// Limits the expression `id` to winning roughly `rate` of the events it
+    /// matches in `matches_sampled`. A rate of 1.0 or more removes the limit.
+    pub fn set_sample_rate(&mut self, id: &str, rate: f32){
+        if rate >= 1.0 {
+            self.sample_rates.remove(id);
+        } else {
+            self.sample_rates.insert(id.into(), rate.max(0.0));
+        }
     }
 
-    #[test]
-    fn calculate_level_for_a_depth_of_four(){
-        let mut leaf = NodeType::new_leaf(LeafNode::new(1));
+    fn sampled_in(id: &str, rate: f32, nonce: u64) -> bool{
+        let mut h = IdHasher::new();
+        id.hash(&mut h);
+        nonce.hash(&mut h);
+        ((h.finish() >> 11) as f64 / (1u64 << 53) as f64) < f64::from(rate)
+    }
 
-        let mut inner = NodeType::new_inner(InnerNode::and());
-        add_children(&mut inner, &mut leaf);
+    /// Replaces the clock deadlines are checked against. Trees start with the
+    /// system clock, or with no clock (deadlines never expire) without `std`.
+    pub fn set_clock(&mut self, clock: Arc<dyn Clock>){
+        self.clock = Some(clock);
+    }
 
-        let mut leaf_two = NodeType::new_leaf(LeafNode::new(2));
+    /// Routes anomalies seen while matching, such as results for unknown
+    /// predicates, to `sink`. Without a sink they are ignored.
+    pub fn set_warning_sink(&mut self, sink: Arc<dyn WarningSink>){
+        self.warning_sink = Some(sink);
+    }
 
-        let mut inner_two = NodeType::new_inner(InnerNode::and());
-        add_children(&mut inner_two,&mut leaf_two);
+    fn warn(&self, warning: Warning){
+        if let Some(sink) = &self.warning_sink {
+            sink.warn(warning);
+        }
+    }
 
-        add_children(&mut inner, &mut inner_two);
+    /// How many nodes `matches_until` processes between two deadline checks.
+    pub fn set_deadline_check_interval(&mut self, interval: usize){
+        self.deadline_check_interval = interval.max(1);
+    }
 
-        let mut root = NodeType::new_root(RootNode::and("1".to_string()));
-        add_children(&mut root, &mut inner);
+    /// The id of the most recent match call, or `None` before the first one.
+    pub fn last_event_id(&self) -> Option<u64>{
+        self.event_ids.with(|ids| ids.last)
+    }
 
-        assert_eq!(root.borrow().get_level(0), 4);
+    /// Ids count up from 0 per tree. A caller-supplied id is used as is and
+    /// moves the counter past it, so later generated ids stay increasing.
+    /// The counter stops at `u64::MAX` instead of wrapping, so from then on
+    /// generated ids repeat rather than go back to earlier ones.
+    fn assign_event_id(&self, supplied: Option<u64>) -> u64{
+        self.event_ids.with(|ids| {
+            let event_id = supplied.unwrap_or(ids.next);
+            ids.next = ids.next.max(event_id.saturating_add(1));
+            ids.last = Some(event_id);
+            event_id
+        })
+    }
 
+    /// Number of distinct nodes in the tree; shared subexpressions count once.
+    pub fn len(&self) -> usize{
+        self.hash_to_node.len()
     }
 
-    #[test]
-    fn insert_three_nodes(){
-        let mut tree = ATree::new();
-        {
-            let mut leaf = NodeType::new_leaf(LeafNode::new(1));
+    pub fn is_empty(&self) -> bool{
+        self.hash_to_node.is_empty()
+    }
 
-            let mut inner = NodeType::new_inner(InnerNode::and());
-            add_children(&mut inner, &mut leaf);
+    /// Same as `clone`: the copy gets its own nodes, so matching on one
+    /// doesn't touch the other.
+    pub fn deep_clone(&self) -> ATree{
+        self.clone()
+    }
 
-            let mut root = NodeType::new_root(RootNode::and("1".to_string()));
-            add_children(&mut root, &mut inner);
+    /// Inserts the expression rooted at `node` and returns a new subscription
+    /// id for it. Inserting a structurally equal expression again shares the
+    /// nodes but still gets its own subscription id.
+    pub fn insert(&mut self, node: ArcNodeLink) -> Result<SubscriptionId, ATreeError>{
+        Self::check_structure(&node)?;
+        Ok(self.subscribe(node))
+    }
 
-            tree.insert(root.clone());
+    /// Like `insert`, but fails with `MissingPredicate` if a leaf references a
+    /// predicate `store` doesn't have, since that expression could never be
+    /// decided.
+    pub fn insert_wired(&mut self, node: ArcNodeLink, store: &PredicateStore) -> Result<SubscriptionId, ATreeError>{
+        Self::check_structure(&node)?;
+        let mut leaf_ids = HashSet::new();
+        Self::collect_leaf_ids(&node, &mut leaf_ids);
+        let mut missing: Vec<u64> = leaf_ids.into_iter().filter(|id| !store.contains(*id)).collect();
+        missing.sort();
+        if let Some(predicate_id) = missing.first() {
+            return Err(ATreeError::MissingPredicate { predicate_id: *predicate_id });
         }
+        Ok(self.subscribe(node))
+    }
 
-        assert_eq!(1, tree.len())
+    fn subscribe(&mut self, node: ArcNodeLink) -> SubscriptionId{
+        let subscription = SubscriptionId(self.next_subscription_id);
+        self.next_subscription_id += 1;
+        let expression_id = match node.borrow().deref() {
+            NodeType::RootNodeType(n) => Some(n.id.clone()),
+            _ => None
+        };
+        let index = self.insert_node(&node);
+        if let (SlotKind::Root{ subscriptions, .. }, Some(expression_id)) = (&mut self.nodes[index].kind, expression_id) {
+            subscriptions.push((subscription, expression_id));
+        }
+        subscription
     }
 
-    #[test]
-    fn insert_two_nodes(){
+    /// Reports predicates in `store` no expression uses and expressions that
+    /// use predicates `store` lacks.
+    pub fn audit_unreferenced(&self, store: &PredicateStore) -> AuditReport{
+        let mut report = AuditReport::default();
+        for index in self.hash_to_node.values() {
+            if let SlotKind::Root{ ids, .. } = &self.nodes[*index].kind {
+                let mut leaf_ids = HashSet::new();
+                self.nodes.collect_leaf_ids(*index, &mut leaf_ids);
+                if leaf_ids.iter().any(|id| !store.contains(*id)) {
+                    report.expressions_with_missing_predicates.extend(ids.iter().cloned());
+                }
+            }
+        }
+        let is_leaf = |id: &u64| self.hash_to_node.get(id)
+            .is_some_and(|index| matches!(self.nodes[*index].kind, SlotKind::Leaf));
+        report.predicates_without_expressions = store.ids().filter(|id| !is_leaf(id)).collect();
+        report.predicates_without_expressions.sort();
+        report.expressions_with_missing_predicates.sort();
+        report
+    }
+
+    fn collect_leaf_ids(node: &ArcNodeLink, ids: &mut HashSet<u64>){
+        match node.borrow().get_children() {
+            Some(childrens) => childrens.iter().for_each(|children| Self::collect_leaf_ids(children, ids)),
+            None => {
+                ids.insert(node.borrow().get_id());
+            }
+        }
+    }
+
+    /// Copies `node` and its children into the arena, reusing nodes already
+    /// there by id.
+    fn insert_node(&mut self, node: &ArcNodeLink) -> NodeId{
+        let id = node.borrow().get_id();
+        if let Some(&existing) = self.hash_to_node.get(&id) {
+            if let NodeType::RootNodeType(n1) = node.borrow().deref() {
+                let slot = &mut self.nodes[existing];
+                match &mut slot.kind {
+                    SlotKind::Root{ ids, .. } => {
+                        ids.insert(n1.id.clone());
+                    }
+                    // Another expression already has this node inside it;
+                    // it becomes a root as well.
+                    SlotKind::Inner{ log_operation } => slot.kind = SlotKind::Root{
+                        log_operation: log_operation.clone(),
+                        ids: HashSet::from_iter([n1.id.clone()]),
+                        subscriptions: vec![]
+                    },
+                    SlotKind::Leaf => {}
+                }
+            }
+            return existing;
+        }
+
+        let children: Vec<NodeId> = node.borrow().get_children().unwrap_or_default().iter()
+            .map(|children| self.insert_node(children))
+            .collect();
+        let level = children.iter().map(|child| self.nodes[*child].level).max().unwrap_or(0) + 1;
+        let kind = match node.borrow().deref() {
+            NodeType::LeafNodeType(_) => SlotKind::Leaf,
+            NodeType::InnerNodeType(n) => SlotKind::Inner{ log_operation: n.log_operation.clone() },
+            NodeType::RootNodeType(n) => SlotKind::Root{
+                log_operation: n.log_operation.clone(),
+                ids: HashSet::from_iter([n.id.clone()]),
+                subscriptions: vec![]
+            }
+        };
+        let index = self.nodes.alloc(NodeSlot::new(id, level, kind, children.clone()));
+        for child in children {
+            self.nodes[child].parents.push(index);
+        }
+        self.hash_to_node.insert(id, index);
+        index
+    }
+
+    /// Removes the expression `expression_id` and returns whether it was in
+    /// the tree. Nodes shared with other expressions stay; a node's parent
+    /// links are its use count, so a node goes once its last parent does.
+    pub fn remove(&mut self, expression_id: &str) -> bool{
+        let root = self.hash_to_node.iter()
+            .find(|(_, index)| matches!(&self.nodes[**index].kind, SlotKind::Root{ ids, .. } if ids.contains(expression_id)))
+            .map(|(id, index)| (*id, *index));
+        let Some((root_id, root)) = root else {
+            return false;
+        };
+        self.sample_rates.remove(expression_id);
+
+        let slot = &mut self.nodes[root];
+        if let SlotKind::Root{ log_operation, ids, subscriptions } = &mut slot.kind {
+            ids.remove(expression_id);
+            subscriptions.retain(|(_, name)| name != expression_id);
+            // Structurally equal expressions share the root; keep it for them.
+            if !ids.is_empty() {
+                return true;
+            }
+            // Part of another expression; it stays as an inner node.
+            if !slot.parents.is_empty() {
+                slot.kind = SlotKind::Inner{ log_operation: log_operation.clone() };
+                return true;
+            }
+        }
+        self.hash_to_node.remove(&root_id);
+        self.release(root);
+        true
+    }
+
+    /// Frees `index` and drops its link from each child, freeing the children
+    /// left without parents in turn.
+    fn release(&mut self, index: NodeId){
+        let Some(node) = self.nodes.release(index) else {
+            return;
+        };
+        for child in node.children {
+            let parents = &mut self.nodes[child].parents;
+            if let Some(position) = parents.iter().position(|p| *p == index) {
+                parents.remove(position);
+            }
+            // Roots stay for their own expressions.
+            if !parents.is_empty() || matches!(self.nodes[child].kind, SlotKind::Root{ .. }) {
+                continue;
+            }
+            let id = self.nodes[child].id;
+            if self.hash_to_node.get(&id) == Some(&child) {
+                self.hash_to_node.remove(&id);
+            }
+            self.release(child);
+        }
+    }
+
+    /// Reports what inserting `nodes` in order would do, without touching the
+    /// tree. Sharing is counted against the existing nodes and against nodes
+    /// created by earlier expressions of the same batch.
+    pub fn plan_insert(&self, nodes: &[ArcNodeLink]) -> InsertPlan{
+        let mut plan = InsertPlan::default();
+        let mut planned = HashSet::new();
+        for node in nodes {
+            let mut expression = ExpressionPlan::default();
+            match Self::check_structure(node) {
+                Ok(()) => self.plan_node(node, &mut planned, &mut expression),
+                Err(e) => expression.validation_issues.push(e)
+            }
+            plan.would_create_nodes += expression.would_create_nodes;
+            plan.would_share_nodes += expression.would_share_nodes;
+            plan.expressions.push(expression);
+        }
+        plan
+    }
+
+    fn plan_node(&self, node: &ArcNodeLink, planned: &mut HashSet<u64>, plan: &mut ExpressionPlan){
+        let id = node.borrow().get_id();
+        if self.hash_to_node.contains_key(&id) || planned.contains(&id) {
+            plan.would_share_nodes += 1;
+            return;
+        }
+        if let Some(childrens) = node.borrow().get_children() {
+            for children in childrens {
+                self.plan_node(children, planned, plan);
+            }
+        }
+        if planned.insert(id) {
+            plan.would_create_nodes += 1;
+        }
+    }
+
+    /// Walks the incoming structure once and fails if a node is its own ancestor
+    /// or a NOT node doesn't have exactly one child. Ids can't be computed on a
+    /// cyclic graph, so the reported `node_id` is the position of the offending
+    /// node in depth-first order (the root is 0).
+    fn check_structure(node: &ArcNodeLink) -> Result<(), ATreeError>{
+        let mut visiting = HashMap::new();
+        let mut visited = HashSet::new();
+        Self::visit(node, &mut visiting, &mut visited)
+    }
+
+    fn visit(node: &ArcNodeLink, visiting: &mut HashMap<usize, u64>, visited: &mut HashSet<usize>) -> Result<(), ATreeError>{
+        let key = Rc::as_ptr(node) as usize;
+        if visited.contains(&key) {
+            return Ok(());
+        }
+        if let Some(node_id) = visiting.get(&key) {
+            return Err(ATreeError::CycleDetected { node_id: *node_id });
+        }
+
+        let position = (visiting.len() + visited.len()) as u64;
+        visiting.insert(key, position);
+        if let NodeType::InnerNodeType(InnerNode{ log_operation: Not, childrens })
+            | NodeType::RootNodeType(RootNode{ log_operation: Not, childrens, .. }) = node.borrow().deref() {
+            if childrens.len() != 1 {
+                return Err(ATreeError::NotArity { node_id: position, children: childrens.len() });
+            }
+        }
+        if let Some(childrens) = node.borrow().get_children() {
+            for children in childrens {
+                Self::visit(children, visiting, visited)?;
+            }
+        }
+        visiting.remove(&key);
+        visited.insert(key);
+        Ok(())
+    }
+
+    /// Returns `(narrower, broader)` pairs of expression ids where every event
+    /// matching the narrower expression also matches the broader one, sorted.
+    /// Ids sharing a root are the same expression; each is reported as
+    /// subsumed by the smallest of them.
+    ///
+    /// The check is structural and conservative: predicates are compared by id
+    /// only (`price > 100` doesn't imply `price > 50`) and no case splitting
+    /// is done, so some real subsumptions are missed.
+    ///
+    /// Reported pairs hold for events that report a result for every
+    /// predicate of the narrower expression. Matching leaves out predicates
+    /// without a result instead of treating them as false, so an event that
+    /// only reports `country` matches `price AND country` but not `price`.
+    pub fn find_subsumed(&self) -> Vec<(String, String)>{
+        let mut roots: Vec<(NodeId, Vec<String>)> = vec![];
+        for index in self.hash_to_node.values() {
+            if let SlotKind::Root{ ids, .. } = &self.nodes[*index].kind {
+                let mut ids: Vec<String> = ids.iter().cloned().collect();
+                ids.sort();
+                roots.push((*index, ids));
+            }
+        }
+
+        let mut subsumed = vec![];
+        for (narrower, narrower_ids) in &roots {
+            for other in &narrower_ids[1..] {
+                subsumed.push((other.clone(), narrower_ids[0].clone()));
+            }
+            for (broader, broader_ids) in &roots {
+                if narrower != broader && self.implies(*narrower, *broader) {
+                    for narrower_id in narrower_ids {
+                        for broader_id in broader_ids {
+                            subsumed.push((narrower_id.clone(), broader_id.clone()));
+                        }
+                    }
+                }
+            }
+        }
+        subsumed.sort();
+        subsumed
+    }
+
+    /// Whether `a` being true guarantees `b` is true, judged from structure alone.
+    fn implies(&self, a: NodeId, b: NodeId) -> bool{
+        if a == b {
+            return true;
+        }
+        let (a_node, b_node) = (&self.nodes[a], &self.nodes[b]);
+        match (a_node.log_operation(), b_node.log_operation()) {
+            (Some(Or), _) => a_node.children.iter().all(|child| self.implies(*child, b)),
+            (_, Some(And)) => b_node.children.iter().all(|child| self.implies(a, *child)),
+            (a_operation, b_operation) => {
+                (matches!(b_operation, Some(Or)) && b_node.children.iter().any(|child| self.implies(a, *child)))
+                    || (matches!(a_operation, Some(And)) && a_node.children.iter().any(|child| self.implies(*child, b)))
+            }
+        }
+    }
+
+    pub fn get_m(&self) -> u32{
+        self.nodes.iter().map(|(_, node)| node.level).max().unwrap_or(0)
+    }
+
+    /// Returns the ids of the expressions that evaluate to true. An empty tree,
+    /// an empty `predicates` slice, or ids the tree doesn't know all simply
+    /// produce no matches.
+    pub fn matches(&self, predicates: &[PredResult]) -> HashSet<String> {
+        self.match_with(predicates, &MatchOptions::default()).matched
+    }
+
+    /// Like `matches`, but returns the subscriptions `insert` handed out for
+    /// the matched expressions.
+    pub fn matched_subscriptions(&self, predicates: &[PredResult]) -> Vec<SubscriptionId> {
+        self.match_with(predicates, &MatchOptions::default()).subscriptions
+    }
+
+    /// Matches `predicates` with the given options. Every other `matches*`
+    /// entry point is this with a single option set.
+    pub fn match_with(&self, predicates: &[PredResult], options: &MatchOptions) -> MatchOutcome {
+        self.matches_iter(predicates.iter().copied(), options)
+    }
+
+    /// Stops propagating once the clock reaches `deadline` and returns the
+    /// matches confirmed so far with `truncated` set.
+    pub fn matches_until(&self, predicates: &[PredResult], deadline: i64) -> MatchOutcome {
+        self.match_with(predicates, &MatchOptions::default().deadline(deadline))
+    }
+
+    /// Like `matches`, but drops matched expressions that lose their sample
+    /// draw. The draw only depends on the expression id and `nonce` (e.g. the
+    /// request id), so replaying an event reproduces the decision.
+    pub fn matches_sampled(&self, predicates: &[PredResult], nonce: u64) -> HashSet<String> {
+        self.match_with(predicates, &MatchOptions::default().sample_nonce(nonce)).matched
+    }
+
+    /// Like `matches`, but also reports the expressions whose root resolved to
+    /// false. Expressions none of whose predicates were supplied are in neither set.
+    pub fn matches_with_outcome(&self, predicates: &[PredResult]) -> MatchOutcome {
+        self.match_with(predicates, &MatchOptions::default())
+    }
+
+    /// Decodes a batch produced by `wire::encode_results` and matches it without
+    /// collecting the results first.
+    pub fn matches_encoded(&self, bytes: &[u8]) -> Result<HashSet<String>, DecodeError> {
+        let predicates = wire::decode_iter(bytes)?;
+        Ok(self.matches_iter(predicates, &MatchOptions::default()).matched)
+    }
+
+    /// The deadline and cancel token are polled every `deadline_check_interval`
+    /// processed nodes; once either fires propagation stops and the outcome is
+    /// marked truncated or cancelled.
+    fn matches_iter(&self, predicates: impl Iterator<Item = PredResult>, options: &MatchOptions) -> MatchOutcome {
+        let expired = || match (options.deadline, &self.clock) {
+            (Some(deadline), Some(clock)) => clock.now_millis() >= deadline,
+            _ => false
+        };
+
+        let mut outcome = MatchOutcome{
+            event_id: self.assign_event_id(options.event_id),
+            ..MatchOutcome::default()
+        };
+        // An empty tree has no levels to queue into; nothing can match.
+        if self.hash_to_node.is_empty() {
+            return outcome;
+        }
+
+        // Indexed by level; index 0 stays empty.
+        let m = self.get_m();
+        let mut queues: Vec<VecDeque<NodeId>> = vec![VecDeque::new(); m as usize + 1];
+        let mut evaluation = Evaluation::new(&self.nodes);
+        // All leaf results are applied before anything is propagated, and each leaf
+        // is queued once. Conflicting results for the same predicate resolve to
+        // unknown, so the outcome doesn't depend on the order of `predicates`.
+        // Unknown results are dropped before they reach a leaf, since they
+        // can't propagate anyway.
+        for predicate in predicates {
+            let result = if options.strict { Some(predicate.result.unwrap_or(false)) } else { predicate.result };
+            if result.is_none() {
+                outcome.skipped_unknowns += 1;
+                if !self.hash_to_node.contains_key(&predicate.id) {
+                    outcome.unreferenced_results += 1;
+                }
+                continue;
+            }
+            let Some(&index) = self.hash_to_node.get(&predicate.id) else {
+                outcome.unreferenced_results += 1;
+                self.warn(Warning::UnknownPredicate { id: predicate.id });
+                continue;
+            };
+            if !matches!(self.nodes[index].kind, SlotKind::Leaf) {
+                self.warn(Warning::NotALeaf { id: predicate.id });
+                continue;
+            }
+            let Some(seeded) = evaluation.get(index) else {
+                evaluation.set(index, result);
+                queues[1].push_front(index);
+                continue;
+            };
+            let conflicting = seeded != result;
+            if conflicting {
+                evaluation.set(index, None);
+            }
+            self.warn(Warning::DuplicateResult { id: predicate.id, conflicting });
+        }
+        if options.strict {
+            for index in &queues[1] {
+                evaluation.set(*index, Some(evaluation.result(*index).unwrap_or(false)));
+            }
+        }
+
+        let mut processed = 0;
+        'levels: for x in 1..=m {
+            // Parents always sit above their children, so a level's queue is
+            // complete by the time it is reached.
+            if options.collect_level_stats {
+                outcome.level_breakdown.push(LevelStats{ level: x, enqueued: queues[x as usize].len(), ..LevelStats::default() });
+            }
+            while let Some(index) = queues[x as usize].pop_front() {
+                if processed % self.deadline_check_interval == 0 {
+                    if options.cancel_token.as_ref().is_some_and(CancelToken::is_cancelled) {
+                        outcome.cancelled = true;
+                    } else if expired() {
+                        outcome.truncated = true;
+                    }
+                    if outcome.cancelled || outcome.truncated {
+                        break 'levels;
+                    }
+                }
+                processed += 1;
+
+                let node = &self.nodes[index];
+                // A node only matters to its parents, so once they are all
+                // decided there is nothing left for it to change.
+                let is_root = matches!(node.kind, SlotKind::Root{ .. });
+                if !is_root && !node.parents.is_empty() && node.parents.iter().all(|parent| evaluation.decided(*parent, &self.nodes[*parent])) {
+                    if let Some(stats) = outcome.level_breakdown.last_mut() {
+                        stats.short_circuited += 1;
+                    }
+                    continue;
+                }
+                let result = evaluation.result(index);
+
+                if let Some(stats) = outcome.level_breakdown.last_mut() {
+                    stats.evaluated += 1;
+                    match result {
+                        Some(true) => stats.resolved_true += 1,
+                        Some(false) => stats.resolved_false += 1,
+                        None => stats.unknown += 1
+                    }
+                }
+
+                if result.is_none() {
+                    continue;
+                }
+
+                for parent_index in &node.parents {
+                    let parent = &self.nodes[*parent_index];
+                    if evaluation.decided(*parent_index, parent) {
+                        continue;
+                    }
+                    if let Some(stats) = outcome.level_breakdown.last_mut() {
+                        stats.deliveries += 1;
+                    }
+                    if evaluation.deliver(*parent_index, parent, result) {
+                        queues[parent.level as usize].push_front(*parent_index);
+                    }
+                }
+
+                if let SlotKind::Root{ ids, subscriptions, .. } = &node.kind {
+                    if result != Some(true) {
+                        outcome.resolved_false.extend(ids.iter().cloned());
+                        outcome.resolved_false_subscriptions.extend(subscriptions.iter().map(|(subscription, _)| *subscription));
+                        continue;
+                    }
+                    for id in ids {
+                        let sampled_out = match (options.sample_nonce, self.sample_rates.get(id)) {
+                            (Some(nonce), Some(rate)) => !Self::sampled_in(id, *rate, nonce),
+                            _ => false
+                        };
+                        if sampled_out {
+                            continue;
+                        }
+                        outcome.total_matched += 1;
+                        if options.max_results.is_some_and(|max| outcome.matched.len() >= max) {
+                            outcome.overflowed = true;
+                            if options.stop_on_limit {
+                                break 'levels;
+                            }
+                        } else {
+                            outcome.matched.insert(id.clone());
+                            outcome.subscriptions.extend(subscriptions.iter().filter(|(_, name)| name == id).map(|(subscription, _)| *subscription));
+                        }
+                    }
+                }
+            }
+        }
+
+        outcome.subscriptions.sort();
+        outcome.resolved_false_subscriptions.sort();
+        outcome
+    }
+}
+
+pub struct EventValue{
+    pub name: String,
+    pub value: Value
+}
+
+pub struct Event{
+    values: Vec<EventValue>
+}
+
+#[derive(Debug, PartialEq)]
+pub enum EventError{
+    NonFinite { attribute: String }
+}
+
+impl Event{
+    pub fn new(values: Vec<EventValue>) -> Self{
+        Event{ values }
+    }
+
+    /// Builds an event for callers that want NaN and infinite doubles
+    /// rejected up front instead of evaluated. Otherwise NaN is unknown to
+    /// every predicate and infinities order normally.
+    pub fn checked(values: Vec<EventValue>) -> Result<Self, EventError>{
+        if let Some(value) = values.iter().find(|v| !v.value.is_finite()) {
+            return Err(EventError::NonFinite { attribute: value.name.clone() });
+        }
+        Ok(Event{ values })
+    }
+}
+
+/// NaN has no answer for any predicate, so it evaluates to unknown.
+fn evaluate_known(predicate: &impl Predicate, value: &Value) -> Option<bool>{
+    if value.is_nan() {
+        None
+    } else {
+        Some(predicate.evaluate(value))
+    }
+}
+
+
+#[derive(Debug, PartialEq)]
+pub enum PredicateError{
+    AlreadyRegistered { other_attribute: String }
+}
+
+/// Mixes the attribute into a predicate id, so identical predicates on
+/// different attributes get distinct leaves. The predicate's kind tag is
+/// kept.
+fn attribute_id(namespace: Namespace, attribute: &str, predicate_id: u64) -> u64{
+    let mut h = IdHasher::new();
+    // No UTF-8 string contains 0xff, so a marked key never hashes like an
+    // attribute name. Exact attributes stay unmarked and keep their ids.
+    if namespace != Namespace::Attribute {
+        h.write_u8(0xff);
+        h.write_u8(namespace as u8);
+    }
+    attribute.hash(&mut h);
+    tagged(tag_of(predicate_id), h.finish() ^ predicate_id)
+}
+
+/// What a predicate is registered under. A pattern or attribute pair never
+/// shares an id with an exact attribute spelled the same.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Namespace{
+    Attribute,
+    Pattern,
+    Cross
+}
+
+pub struct PredicateStore{
+    predicates: HashMap<String, Vec<(u64, PredicateKind)>>,
+    patterns: Vec<(AttributePattern, Vec<(u64, PredicateKind)>)>,
+    cross: Vec<(u64, CrossAttrPredicate)>,
+    attributes: HashMap<u64, (Namespace, String)>,
+    context: Option<Event>,
+    fallbacks: Vec<(String, Vec<String>)>
+}
+
+
+impl Default for PredicateStore{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PredicateStore {
+
+    pub fn new() -> Self{
+        Self{
+            predicates: HashMap::new(),
+            patterns: vec![],
+            cross: vec![],
+            attributes: HashMap::new(),
+            context: None,
+            fallbacks: vec![]
+        }
+    }
+
+    /// Default attribute values used when an evaluated event doesn't carry the
+    /// attribute itself. Values on the event always win.
+    pub fn set_context(&mut self, context: Event){
+        self.context = Some(context);
+    }
+
+    pub fn clear_context(&mut self){
+        self.context = None;
+    }
+
+    /// Lets `attribute` take its value from the first attribute in `chain`
+    /// that an event provides, e.g. `geo.country` from `device.geo.country`
+    /// before `user.geo.country`. An event that provides `attribute` itself
+    /// keeps its own value. Setting a chain again replaces the old one.
+    pub fn fallback(&mut self, attribute: &str, chain: &[&str]){
+        let chain = chain.iter().map(|name| String::from(*name)).collect();
+        match self.fallbacks.iter_mut().find(|(existing, _)| existing == attribute) {
+            Some((_, existing)) => *existing = chain,
+            None => self.fallbacks.push((attribute.into(), chain))
+        }
+    }
+
+    /// Values for fallback attributes the event and context don't provide.
+    fn resolve_fallbacks<'a>(&self, values: impl Iterator<Item = &'a EventValue> + Clone) -> Vec<EventValue> {
+        self.fallbacks.iter()
+            .filter(|(attribute, _)| !values.clone().any(|f| &f.name == attribute))
+            .filter_map(|(attribute, chain)| {
+                chain.iter()
+                    .find_map(|name| values.clone().find(|f| &f.name == name))
+                    .map(|f| EventValue{ name: attribute.clone(), value: f.value.clone() })
+            })
+            .collect()
+    }
+
+    /// Registers `p` under `attribute` and returns the id its results are
+    /// reported with. Adding the same predicate to the same attribute again
+    /// returns the existing id; an id already owned by another attribute is
+    /// rejected rather than reported twice.
+    pub fn add(&mut self, attribute: String, p: impl Predicate + 'static) -> Result<u64, PredicateError> {
+        let (id, is_new) = self.register(Namespace::Attribute, &attribute, p.id())?;
+        if is_new {
+            self.predicates.entry(attribute).or_default().push((id, p.into_kind()));
+        }
+        Ok(id)
+    }
+
+    /// Registers `p` under an attribute pattern such as `*.geo.country`. The
+    /// predicate is true for an event if it holds for any attribute matching
+    /// the pattern, and is not reported when no attribute matches.
+    pub fn add_pattern(&mut self, pattern: &str, p: impl Predicate + 'static) -> Result<u64, PredicateError> {
+        let (id, is_new) = self.register(Namespace::Pattern, pattern, p.id())?;
+        if is_new {
+            match self.patterns.iter_mut().find(|(existing, _)| existing.as_str() == pattern) {
+                Some((_, predicates)) => predicates.push((id, p.into_kind())),
+                None => self.patterns.push((AttributePattern::new(pattern), vec![(id, p.into_kind())]))
+            }
+        }
+        Ok(id)
+    }
+
+    pub fn contains(&self, id: u64) -> bool {
+        self.attributes.contains_key(&id)
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = u64> + '_ {
+        self.attributes.keys().copied()
+    }
+
+    /// Registers a comparison between two attributes. It reports a result
+    /// once per event: true or false when both attributes are present,
+    /// unknown when only one is, and nothing when neither is.
+    pub fn add_cross(&mut self, p: CrossAttrPredicate) -> Result<u64, PredicateError> {
+        let attributes = [p.left_attr(), p.right_attr()].join(",");
+        let (id, is_new) = self.register(Namespace::Cross, &attributes, p.id())?;
+        if is_new {
+            self.cross.push((id, p));
+        }
+        Ok(id)
+    }
+
+    fn register(&mut self, namespace: Namespace, attribute: &str, predicate_id: u64) -> Result<(u64, bool), PredicateError> {
+        let id = attribute_id(namespace, attribute, predicate_id);
+        match self.attributes.get(&id) {
+            Some((other_namespace, other_attribute)) if *other_namespace == namespace && other_attribute == attribute => Ok((id, false)),
+            Some((_, other_attribute)) => Err(PredicateError::AlreadyRegistered { other_attribute: other_attribute.clone() }),
+            None => {
+                self.attributes.insert(id, (namespace, attribute.into()));
+                Ok((id, true))
+            }
+        }
+    }
+
+    pub fn evaluate(&self, event: &Event) -> Vec<PredResult> {
+        // Heartbeat-style events carry a single attribute; look it up directly
+        // instead of scanning every attribute the store knows.
+        if let ([value], None, true) = (event.values.as_slice(), &self.context, self.fallbacks.is_empty()) {
+            return self.evaluate_single(value);
+        }
+        let context = self.context.iter().flat_map(|context| context.values.iter());
+        let values = event.values.iter().chain(context);
+        let resolved = self.resolve_fallbacks(values.clone());
+        self.evaluate_values(values.chain(resolved.iter()))
+    }
+
+    fn evaluate_single(&self, value: &EventValue) -> Vec<PredResult> {
+        let exact = self.predicates.get(&value.name).into_iter().flatten();
+        let patterns = self.patterns.iter()
+            .filter(|(pattern, _)| pattern.matches(&value.name))
+            .flat_map(|(_, predicates)| predicates);
+        let mut result: Vec<PredResult> = exact.chain(patterns)
+            .map(|(id, predicate)| PredResult{ id: *id, result: evaluate_known(predicate, &value.value) })
+            .collect();
+        self.evaluate_cross(core::iter::once(value), &mut result);
+        result
+    }
+
+    fn evaluate_cross<'a>(&self, values: impl Iterator<Item = &'a EventValue> + Clone, result: &mut Vec<PredResult>) {
+        for (id, predicate) in &self.cross {
+            let find = |attribute: &str| values.clone().find(|f| f.name == attribute).map(|f| &f.value);
+            let evaluated = match (find(predicate.left_attr()), find(predicate.right_attr())) {
+                (Some(left), Some(right)) if left.is_nan() || right.is_nan() => None,
+                (Some(left), Some(right)) => Some(predicate.evaluate(left, right)),
+                (None, None) => continue,
+                _ => None
+            };
+            result.push(PredResult{ id: *id, result: evaluated });
+        }
+    }
+
+    /// Evaluates against the first value of each attribute in `values`, so
+    /// earlier sources shadow later ones.
+    fn evaluate_values<'a>(&self, values: impl Iterator<Item = &'a EventValue> + Clone) -> Vec<PredResult> {
+        let mut result = vec![];
+        for x in &self.predicates {
+            let event = values.clone().find(|&f| { f.name.eq(x.0) });
+            if let Some(event) = event {
+                for (id, predicate) in x.1 {
+                    result.push(PredResult{
+                        id: *id,
+                        result: evaluate_known(predicate, &event.value)
+                    })
+                }
+            }
+        }
+
+        for (pattern, predicates) in &self.patterns {
+            let mut matching = values.clone().enumerate()
+                .filter(|(_, f)| pattern.matches(&f.name))
+                .filter(|(i, f)| !values.clone().take(*i).any(|earlier| earlier.name == f.name))
+                .map(|(_, f)| &f.value)
+                .peekable();
+            if matching.peek().is_none() {
+                continue;
+            }
+            let matching: Vec<&Value> = matching.collect();
+            for (id, predicate) in predicates {
+                result.push(PredResult{
+                    id: *id,
+                    result: matching.iter().map(|value| evaluate_known(predicate, value)).fold(Some(false), kleene_or)
+                })
+            }
+        }
+
+        self.evaluate_cross(values, &mut result);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::predicates::Value::Int;
+    use crate::predicates::{predicate_kind_of, PredicateKindTag};
+    use crate::collections::HashSet;
+    use alloc::string::ToString;
+    use crate::clock::ManualClock;
+    use crate::test_rng::XorShift;
+    use core::sync::atomic::AtomicUsize;
+
+    #[test]
+    fn calculate_level_for_three_nodes(){
+        let mut leaf = NodeType::new_leaf(LeafNode::new(1));
+
+        let mut inner = NodeType::new_inner(InnerNode::and());
+        add_children(&mut inner, &mut leaf);
+
+        let mut root = NodeType::new_root(RootNode::and("1".to_string()));
+        add_children(&mut root, &mut inner);
+
+
+        assert_eq!(root.borrow().get_level(), 3);
+    }
+
+    #[test]
+    fn calculate_level_for_a_depth_of_four(){
+        let mut leaf = NodeType::new_leaf(LeafNode::new(1));
+
+        let mut inner = NodeType::new_inner(InnerNode::and());
+        add_children(&mut inner, &mut leaf);
+
+        let mut leaf_two = NodeType::new_leaf(LeafNode::new(2));
+
+        let mut inner_two = NodeType::new_inner(InnerNode::and());
+        add_children(&mut inner_two,&mut leaf_two);
+
+        add_children(&mut inner, &mut inner_two);
+
+        let mut root = NodeType::new_root(RootNode::and("1".to_string()));
+        add_children(&mut root, &mut inner);
+
+        assert_eq!(root.borrow().get_level(), 4);
+
+    }
+
+    #[test]
+    fn levels_count_leaves_as_one_on_every_path(){
+        let mut leaf = NodeType::new_leaf(LeafNode::new(1));
+        assert_eq!(1, leaf.borrow().get_level());
+
+        let mut root = NodeType::new_root(RootNode::or("1".to_string()));
+        add_children(&mut root, &mut leaf);
+        assert_eq!(2, root.borrow().get_level());
+
+        let mut inner = NodeType::new_inner(InnerNode::and());
+        add_children(&mut inner, &mut NodeType::new_leaf(LeafNode::new(2)));
+        add_children(&mut inner, &mut NodeType::new_leaf(LeafNode::new(3)));
+        assert_eq!(2, inner.borrow().get_level());
+
+        add_children(&mut root, &mut inner);
+        assert_eq!(3, root.borrow().get_level());
+        assert_eq!(1, leaf.borrow().get_level());
+    }
+
+    #[test]
+    fn insert_three_nodes(){
+        let mut tree = ATree::new();
+        {
+            let mut leaf = NodeType::new_leaf(LeafNode::new(1));
+
+            let mut inner = NodeType::new_inner(InnerNode::and());
+            add_children(&mut inner, &mut leaf);
+
+            let mut root = NodeType::new_root(RootNode::and("1".to_string()));
+            add_children(&mut root, &mut inner);
+
+            tree.insert(root.clone()).unwrap();
+        }
+
+        assert_eq!(3, tree.len())
+    }
+
+    #[test]
+    fn insert_two_nodes(){
+        let mut tree = ATree::new();
+        {
+            let mut leaf = NodeType::new_leaf(LeafNode::new(1));
+            let mut leaf_two = NodeType::new_leaf(LeafNode::new(2));
+
+            let mut root = NodeType::new_root(RootNode::and("1".to_string()));
+            add_children(&mut root, &mut leaf);
+            add_children(&mut root, &mut leaf_two);
+
+            tree.insert(root.clone()).unwrap();
+        }
+
+        assert_eq!(3, tree.len());
+        assert_eq!(2, tree.get_m());
+    }
+
+    #[test]
+    fn insert_two_same_root_nodes(){
+        let mut tree = ATree::new();
+        {
+            let mut leaf = NodeType::new_leaf(LeafNode::new(1));
+            let mut leaf_two = NodeType::new_leaf(LeafNode::new(2));
+
+            let mut inner = NodeType::new_inner(InnerNode::and());
+            add_children(&mut inner, &mut leaf);
+            add_children(&mut inner, &mut leaf_two);
+
+            let mut root = NodeType::new_root(RootNode::and("1".to_string()));
+            add_children(&mut root,&mut inner);
+
+            tree.insert(root.clone()).unwrap();
+        }
+
+        {
+            let mut leaf = NodeType::new_leaf(LeafNode::new(1));
+            let mut leaf_two = NodeType::new_leaf(LeafNode::new(2));
+
+            let mut inner = NodeType::new_inner(InnerNode::and());
+            add_children(&mut inner, &mut leaf);
+            add_children(&mut inner, &mut leaf_two);
+
+            let mut root = NodeType::new_root(RootNode::and("1".to_string()));
+            add_children(&mut root,&mut inner);
+
+            tree.insert(root.clone()).unwrap();
+        }
+
+        assert_eq!(4, tree.len());
+        assert_eq!(3, tree.get_m());
+    }
+
+    #[test]
+    fn insert_two_dif_root_nodes(){
+        let mut tree = ATree::new();
+        {
+            let mut leaf = NodeType::new_leaf(LeafNode::new(4));
+            let mut leaf_two = NodeType::new_leaf(LeafNode::new(6));
+
+            let mut inner = NodeType::new_inner(InnerNode::and());
+            add_children(&mut inner, &mut leaf);
+            add_children(&mut inner, &mut leaf_two);
+
+            let mut root = NodeType::new_root(RootNode::and("1".to_string()));
+            add_children(&mut root,&mut inner);
+
+            tree.insert(root.clone()).unwrap();
+        }
+
+        {
+            let mut leaf = NodeType::new_leaf(LeafNode::new(8));
+            let mut leaf_two = NodeType::new_leaf(LeafNode::new(2));
+
+            let mut inner = NodeType::new_inner(InnerNode::or());
+            add_children(&mut inner, &mut leaf);
+            add_children(&mut inner, &mut leaf_two);
+
+            let mut root = NodeType::new_root(RootNode::and("1".to_string()));
+            add_children(&mut root,&mut inner);
+
+            tree.insert(root.clone()).unwrap();
+        }
+
+        assert_eq!(8, tree.len());
+        assert_eq!(3, tree.get_m());
+    }
+
+    #[test]
+    fn insert_two_dif_root_and_m_4_nodes(){
+        let mut tree = ATree::new();
+        {
+            let mut leaf_one = NodeType::new_leaf(LeafNode::new(4));
+            let mut leaf_two = NodeType::new_leaf(LeafNode::new(6));
+
+
+
+            let mut root_inner_1_inner_1 = NodeType::new_inner(InnerNode::and());
+            add_children(&mut root_inner_1_inner_1, &mut leaf_one);
+            add_children(&mut root_inner_1_inner_1, &mut leaf_two);
+            let mut root_inner_1_inner_2 = NodeType::new_inner(InnerNode::or());
+            add_children(&mut root_inner_1_inner_2, &mut leaf_one);
+            add_children(&mut root_inner_1_inner_2, &mut leaf_two);
+
+            let mut root_inner_2_inner_1 = NodeType::new_inner(InnerNode::and());
+            add_children(&mut root_inner_2_inner_1, &mut leaf_one);
+            add_children(&mut root_inner_2_inner_1, &mut leaf_two);
+            let mut root_inner_2_inner_2 = NodeType::new_inner(InnerNode::and());
+            add_children(&mut root_inner_2_inner_2, &mut leaf_one);
+            add_children(&mut root_inner_2_inner_2, &mut leaf_two);
+
+            let mut root_inner_1 = NodeType::new_inner(InnerNode::and());
+            add_children(&mut root_inner_1, &mut root_inner_1_inner_1);
+            add_children(&mut root_inner_1, &mut root_inner_1_inner_2);
+            let mut root_inner_2 = NodeType::new_inner(InnerNode::and());
+            add_children(&mut root_inner_2, &mut root_inner_2_inner_1);
+            add_children(&mut root_inner_2, &mut root_inner_2_inner_2);
+
+
+            let mut root = NodeType::new_root(RootNode::and("1".to_string()));
+            add_children(&mut root,&mut root_inner_1);
+            add_children(&mut root,&mut root_inner_2);
+
+            tree.insert(root.clone()).unwrap();
+        }
+
+
+
+        assert_eq!(4, tree.get_m());
+    }
+
+    #[test]
+    fn insert_rejects_cycle(){
+        let mut tree = ATree::new();
+        let mut leaf = NodeType::new_leaf(LeafNode::new(1));
+
+        let mut inner = NodeType::new_inner(InnerNode::and());
+        let mut inner_two = NodeType::new_inner(InnerNode::or());
+        add_children(&mut inner, &mut leaf);
+        add_children(&mut inner, &mut inner_two);
+        add_children(&mut inner_two, &mut inner);
+
+        let mut root = NodeType::new_root(RootNode::and("1".to_string()));
+        add_children(&mut root, &mut inner);
+
+        assert_eq!(Err(ATreeError::CycleDetected { node_id: 1 }), tree.insert(root.clone()).map(|_| ()));
+        assert_eq!(0, tree.len());
+    }
+
+    #[test]
+    fn insert_rejects_node_as_its_own_child(){
+        let mut tree = ATree::new();
+        let mut inner = NodeType::new_inner(InnerNode::and());
+        let mut same = inner.clone();
+        add_children(&mut inner, &mut same);
+
+        let mut root = NodeType::new_root(RootNode::and("1".to_string()));
+        add_children(&mut root, &mut inner);
+
+        assert_eq!(Err(ATreeError::CycleDetected { node_id: 1 }), tree.insert(root.clone()).map(|_| ()));
+    }
+
+    #[test]
+    fn insert_accepts_shared_children(){
+        let mut tree = ATree::new();
+        let mut leaf = NodeType::new_leaf(LeafNode::new(1));
+        let mut leaf_two = NodeType::new_leaf(LeafNode::new(2));
+
+        let mut inner = NodeType::new_inner(InnerNode::and());
+        add_children(&mut inner, &mut leaf);
+        add_children(&mut inner, &mut leaf_two);
+        let mut inner_two = NodeType::new_inner(InnerNode::or());
+        add_children(&mut inner_two, &mut leaf);
+        add_children(&mut inner_two, &mut leaf_two);
+
+        let mut root = NodeType::new_root(RootNode::and("1".to_string()));
+        add_children(&mut root, &mut inner);
+        add_children(&mut root, &mut inner_two);
+
+        assert!(tree.insert(root.clone()).is_ok());
+    }
+
+    fn random_inner(rng: &mut XorShift, leaf_ids: &[u64], depth: u32) -> ArcNodeLink{
+        let mut inner = if rng.below(2) == 0 {
+            NodeType::new_inner(InnerNode::and())
+        } else {
+            NodeType::new_inner(InnerNode::or())
+        };
+        for _ in 0..2 + rng.below(2) {
+            let mut child = if depth > 0 && rng.below(3) == 0 {
+                random_inner(rng, leaf_ids, depth - 1)
+            } else {
+                NodeType::new_leaf(LeafNode::new(leaf_ids[rng.below(leaf_ids.len() as u64) as usize]))
+            };
+            add_children(&mut inner, &mut child);
+        }
+        inner
+    }
+
+    fn random_tree(rng: &mut XorShift, leaf_ids: &[u64]) -> ATree{
+        let mut tree = ATree::new();
+        for expression in 0..5 {
+            let mut root = if rng.below(2) == 0 {
+                NodeType::new_root(RootNode::and(expression.to_string()))
+            } else {
+                NodeType::new_root(RootNode::or(expression.to_string()))
+            };
+            for _ in 0..2 + rng.below(2) {
+                let mut child = if rng.below(2) == 0 {
+                    random_inner(rng, leaf_ids, 2)
+                } else {
+                    NodeType::new_leaf(LeafNode::new(leaf_ids[rng.below(leaf_ids.len() as u64) as usize]))
+                };
+                add_children(&mut root, &mut child);
+            }
+            tree.insert(root).unwrap();
+        }
+        tree
+    }
+
+    #[cfg(feature = "std")]
+    fn random_value(rng: &mut XorShift) -> Value{
+        match rng.below(8) {
+            0 => Int(rng.next() as i32),
+            1 => Value::Double(predicates::Double::new(f64::from_bits(rng.next()))),
+            2 => Value::Double(predicates::Double::new([f64::NAN, f64::INFINITY, f64::NEG_INFINITY, -0.0][rng.below(4) as usize])),
+            3 => Value::String(["", "X", "file10", "\u{0}"][rng.below(4) as usize].to_string()),
+            4 => Value::Bool(rng.below(2) == 0),
+            5 => Value::Binary((0..rng.below(4)).map(|_| rng.next() as u8).collect()),
+            _ => Int(rng.below(8) as i32)
+        }
+    }
+
+    // The long randomized loops are skipped under Miri; tests/miri_smoke.rs
+    // runs a short cycle of the same operations there.
+    #[cfg(feature = "std")]
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn fuzzed_matching_never_panics(){
+        use crate::predicates::{CrossOperation, OrdOperation};
+        use std::panic::{catch_unwind, AssertUnwindSafe};
+
+        let mut rng = XorShift(0xD1B5_4A32_D192_ED03);
+        let leaf_ids: Vec<u64> = (0..6).map(|_| rng.next() | 1 << 40).collect();
+        let mut tree = random_tree(&mut rng, &leaf_ids);
+        let pristine = tree.deep_clone();
+        let node_ids: Vec<u64> = tree.hash_to_node.keys().copied().collect();
+
+        let mut store = PredicateStore::new();
+        store.add("a".to_string(), predicates::greater(Int(3))).unwrap();
+        store.add("b".to_string(), predicates::equal(Value::String("X".to_string()))).unwrap();
+        store.add("b".to_string(), predicates::between(Int(0), Int(5)).with_collation(predicates::Collation::NumericAware)).unwrap();
+        store.add_pattern("*.c", predicates::element_of(vec![Int(1), Int(2)]).with_bloom(8)).unwrap();
+        store.add_cross(CrossAttrPredicate::new("a", "z", CrossOperation::Ord(OrdOperation::Less))).unwrap();
+        store.fallback("z", &["x.c", "y.c"]);
+
+        for round in 0..5000 {
+            let results: Vec<PredResult> = (0..rng.below(12)).map(|_| {
+                let id = match rng.below(5) {
+                    0 => leaf_ids[rng.below(leaf_ids.len() as u64) as usize],
+                    1 => node_ids[rng.below(node_ids.len() as u64) as usize],
+                    2 => u64::MAX,
+                    3 => 0,
+                    _ => rng.next()
+                };
+                let result = [Some(true), Some(false), None][rng.below(3) as usize];
+                PredResult::new(id, result)
+            }).collect();
+            let event = Event::new((0..rng.below(5)).map(|_| EventValue{
+                name: ["a", "b", "x.c", "y.c", "z"][rng.below(5) as usize].to_string(),
+                value: random_value(&mut rng)
+            }).collect());
+            let options = MatchOptions::default()
+                .strict(rng.below(2) == 0)
+                .max_results(rng.below(4) as usize)
+                .stop_on_limit(rng.below(2) == 0)
+                .collect_level_stats(rng.below(2) == 0);
+            let garbage: Vec<u8> = (0..rng.below(24)).map(|_| rng.next() as u8).collect();
+            tree.set_deadline_check_interval(rng.below(3) as usize);
+
+            let outcome = catch_unwind(AssertUnwindSafe(|| {
+                let mut results = results.clone();
+                results.extend(store.evaluate(&event));
+                tree.match_with(&results, &options);
+                tree.matches(&[]);
+                let mut bytes = vec![];
+                wire::encode_results(&results, &mut bytes);
+                tree.matches_encoded(&bytes).unwrap();
+                let _ = tree.matches_encoded(&garbage);
+            }));
+            assert!(outcome.is_ok(), "round {} panicked", round);
+        }
+
+        for result in [Some(true), Some(false)] {
+            let probe: Vec<PredResult> = leaf_ids.iter().map(|id| PredResult::new(*id, result)).collect();
+            assert_eq!(pristine.matches(&probe), tree.matches(&probe));
+        }
+    }
+
+    #[test]
+    fn matches_is_insensitive_to_predicate_order(){
+        let mut rng = XorShift(0x9E37_79B9_7F4A_7C15);
+        for _ in 0..20 {
+            let leaf_ids: Vec<u64> = (0..6).map(|_| rng.next() | 1 << 40).collect();
+            let tree = random_tree(&mut rng, &leaf_ids);
+
+            let mut results = vec![];
+            for id in &leaf_ids {
+                for _ in 0..rng.below(3) {
+                    let result = match rng.below(3) {
+                        0 => Some(true),
+                        1 => Some(false),
+                        _ => None
+                    };
+                    results.push(PredResult{ id: *id, result });
+                }
+            }
+
+            let expected = tree.matches(&results);
+            for _ in 0..100 {
+                rng.shuffle(&mut results);
+                assert_eq!(expected, tree.matches(&results));
+            }
+        }
+    }
+
+    #[test]
+    fn conflicting_results_for_a_predicate_are_unknown(){
+        let mut tree = ATree::new();
+        let mut leaf = NodeType::new_leaf(LeafNode::new(1));
+        let mut leaf_two = NodeType::new_leaf(LeafNode::new(2));
+
+        let mut root = NodeType::new_root(RootNode::or("1".to_string()));
+        add_children(&mut root, &mut leaf);
+        add_children(&mut root, &mut leaf_two);
+        tree.insert(root).unwrap();
+
+        let first = vec![PredResult{ id: 1, result: Some(true) }, PredResult{ id: 1, result: Some(false) }];
+        let second = vec![PredResult{ id: 1, result: Some(false) }, PredResult{ id: 1, result: Some(true) }];
+
+        assert!(tree.matches(&first).is_empty());
+        assert!(tree.matches(&second).is_empty());
+    }
+
+    #[test]
+    fn duplicate_delivery_of_a_result_is_harmless(){
+        let mut tree = ATree::new();
+        tree.insert(and_of_any_and_extra("and", &[1, 2])).unwrap();
+        let ids = [1, 2, 101].map(leaf_id);
+
+        let mut results: Vec<PredResult> = ids.iter().map(|id| PredResult{ id: *id, result: Some(true) }).collect();
+        results[0].result = Some(false);
+        let once = tree.matches_with_outcome(&results);
+        assert_eq!(HashSet::from(["and".to_string()]), once.matched);
+
+        let mut twice = results.clone();
+        twice.extend(results.iter().copied());
+        twice.push(results[2]);
+        let outcome = tree.match_with(&twice, &MatchOptions::default().event_id(once.event_id));
+        assert_eq!(once, outcome);
+
+        results[2].result = Some(false);
+        twice = results.iter().chain(results.iter()).copied().collect();
+        assert!(tree.matches(&twice).is_empty());
+    }
+
+    #[test]
+    fn unknown_results_leave_leaves_untouched(){
+        let mut tree = ATree::new();
+        tree.insert(and_of_any_and_extra("and", &[1, 2])).unwrap();
+        let [one, two, extra] = [1, 2, 101].map(leaf_id);
+
+        let outcome = tree.matches_with_outcome(&[PredResult{ id: one, result: None }, PredResult{ id: extra, result: None }]);
+        assert_eq!(2, outcome.skipped_unknowns);
+        assert!(outcome.matched.is_empty() && outcome.resolved_false.is_empty());
+
+        let results = [
+            PredResult{ id: one, result: None },
+            PredResult{ id: two, result: Some(true) },
+            PredResult{ id: extra, result: Some(true) },
+        ];
+        let outcome = tree.matches_with_outcome(&results);
+        assert_eq!(1, outcome.skipped_unknowns);
+        assert_eq!(HashSet::from(["and".to_string()]), outcome.matched);
+        assert_eq!(0, tree.match_with(&results, &MatchOptions::default().strict(true)).skipped_unknowns);
+    }
+
+    #[test]
+    fn root_over_leaves_reports_exactly_one_match(){
+        let mut tree = ATree::new();
+        tree.insert(expression("1", And, &mut [leaf(1), leaf(2)])).unwrap();
+        assert_eq!(2, tree.get_m());
+
+        let results = [PredResult::new(leaf_id(1), Some(true)), PredResult::new(leaf_id(2), Some(true))];
+        assert_eq!(HashSet::from_iter(["1".to_string()]), tree.matches(&results));
+        let results = [PredResult::new(leaf_id(1), Some(true)), PredResult::new(leaf_id(2), Some(false))];
+        assert!(tree.matches(&results).is_empty());
+    }
+
+    #[test]
+    fn root_over_inner_node_reports_only_the_root(){
+        let mut tree = ATree::new();
+        tree.insert(expression("1", And, &mut [leaf(1), or_of(&[2, 3])])).unwrap();
+        assert_eq!(3, tree.get_m());
+
+        let results = [PredResult::new(leaf_id(1), Some(true)), PredResult::new(leaf_id(3), Some(true))];
+        let outcome = tree.match_with(&results, &MatchOptions::default().collect_level_stats(true));
+        assert_eq!(HashSet::from_iter(["1".to_string()]), outcome.matched);
+        assert_eq!(1, outcome.total_matched);
+        assert_eq!(vec![2, 1, 1], outcome.level_breakdown.iter().map(|level| level.resolved_true).collect::<Vec<_>>());
+
+        // The inner node alone being true is not a match.
+        let results = [PredResult::new(leaf_id(1), Some(false)), PredResult::new(leaf_id(2), Some(true))];
+        assert!(tree.matches(&results).is_empty());
+    }
+
+    #[test]
+    fn shallow_and_degenerate_trees_do_not_panic(){
+        let results = [PredResult::new(leaf_id(1), Some(true)), PredResult::new(leaf_id(2), Some(true))];
+
+        // A bare leaf inserted as an expression: m == 1.
+        let mut tree = ATree::new();
+        tree.insert(leaf(1)).unwrap();
+        assert_eq!(1, tree.get_m());
+        assert!(tree.matches(&results).is_empty());
+
+        // A root over a single leaf: m == 2.
+        let mut tree = ATree::new();
+        tree.insert(expression("1", Or, &mut [leaf(1)])).unwrap();
+        assert_eq!(2, tree.get_m());
+        assert_eq!(HashSet::from_iter(["1".to_string()]), tree.matches(&results));
+        assert!(tree.matches(&[]).is_empty());
+
+        // Parents on the top level are queued like any other.
+        let mut tree = ATree::new();
+        tree.insert(expression("1", And, &mut [leaf(1), leaf(2)])).unwrap();
+        assert_eq!(2, tree.get_m());
+        for _ in 0..3 {
+            assert_eq!(HashSet::from_iter(["1".to_string()]), tree.matches(&results));
+        }
+    }
+
+    #[test]
+    fn matches_encoded_equals_matches(){
+        let mut tree = ATree::new();
+        let mut leaf = NodeType::new_leaf(LeafNode::new(1));
+        let mut leaf_two = NodeType::new_leaf(LeafNode::new(2));
+
+        let mut root = NodeType::new_root(RootNode::and("1".to_string()));
+        add_children(&mut root, &mut leaf);
+        add_children(&mut root, &mut leaf_two);
+        tree.insert(root).unwrap();
+
+        let results = vec![PredResult{ id: 1, result: Some(true) }, PredResult{ id: 2, result: Some(true) }];
+        let mut bytes = vec![];
+        wire::encode_results(&results, &mut bytes);
+
+        assert_eq!(tree.matches(&results), tree.matches_encoded(&bytes).unwrap());
+        assert_eq!(Err(DecodeError::Empty), tree.matches_encoded(&[]));
+        assert!(tree.matches_encoded(&bytes[..bytes.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn outcome_separates_false_from_untouched(){
+        let mut tree = ATree::new();
+        let mut subscriptions = vec![];
+        for (id, predicates) in [("true", [1, 2]), ("false", [3, 4]), ("untouched", [5, 6])] {
+            let mut leaf = NodeType::new_leaf(LeafNode::new(predicates[0] << 32));
+            let mut leaf_two = NodeType::new_leaf(LeafNode::new(predicates[1] << 32));
+
+            let mut root = NodeType::new_root(RootNode::and(id.to_string()));
+            add_children(&mut root, &mut leaf);
+            add_children(&mut root, &mut leaf_two);
+            subscriptions.push(tree.insert(root).unwrap());
+        }
+
+        let outcome = tree.matches_with_outcome(&[
+            PredResult{ id: 1 << 32, result: Some(true) },
+            PredResult{ id: 2 << 32, result: Some(true) },
+            PredResult{ id: 3 << 32, result: Some(true) },
+            PredResult{ id: 4 << 32, result: Some(false) },
+        ]);
+
+        assert_eq!(HashSet::from(["true".to_string()]), outcome.matched);
+        assert_eq!(HashSet::from(["false".to_string()]), outcome.resolved_false);
+        assert_eq!(vec![subscriptions[0]], outcome.subscriptions);
+        assert_eq!(vec![subscriptions[1]], outcome.resolved_false_subscriptions);
+    }
+
+    fn wide_tree(expressions: u64) -> (ATree, Vec<PredResult>){
+        let mut tree = ATree::new();
+        let mut results = vec![];
+        for expression in 0..expressions {
+            let ids: Vec<u64> = (0..3).map(|leaf| leaf_id(expression * 3 + leaf + 1)).collect();
+            let mut leaf = NodeType::new_leaf(LeafNode::new(ids[0]));
+            let mut leaf_two = NodeType::new_leaf(LeafNode::new(ids[1]));
+            let mut leaf_three = NodeType::new_leaf(LeafNode::new(ids[2]));
+
+            let mut inner = NodeType::new_inner(InnerNode::or());
+            add_children(&mut inner, &mut leaf_two);
+            add_children(&mut inner, &mut leaf_three);
+
+            let mut root = NodeType::new_root(RootNode::and(expression.to_string()));
+            add_children(&mut root, &mut leaf);
+            add_children(&mut root, &mut inner);
+            tree.insert(root).unwrap();
+
+            results.extend(ids.into_iter().map(|id| PredResult{ id, result: Some(true) }));
+        }
+        (tree, results)
+    }
+
+    #[test]
+    fn matches_until_truncates_after_deadline_and_leaves_tree_clean(){
+        let (mut tree, results) = wide_tree(200);
+        let clock = Arc::new(ManualClock::new(1_000));
+        tree.set_clock(clock.clone());
+        tree.set_deadline_check_interval(16);
+
+        let outcome = tree.matches_until(&results, 1_000);
+        assert!(outcome.truncated);
+        assert!(outcome.matched.is_empty());
+
+        let outcome = tree.matches_until(&results[..3], 1_001);
+        assert!(!outcome.truncated);
+        assert_eq!(HashSet::from(["0".to_string()]), outcome.matched);
+        assert!(outcome.resolved_false.is_empty());
+        assert_eq!(200, tree.matches(&results).len());
+
+        clock.advance(1);
+        assert!(tree.matches_until(&results, 1_001).truncated);
+    }
+
+    #[test]
+    fn context_supplies_missing_attributes(){
+        let mut pm = PredicateStore::new();
+        let exchange_id = pm.add("exchange".to_string(), predicates::equal(Value::String("X".to_string()))).unwrap();
+        let price_id = pm.add("price".to_string(), predicates::greater(Int(5))).unwrap();
+
+        pm.set_context(Event{
+            values: vec![EventValue{ name: "exchange".to_string(), value: Value::String("X".to_string()) }]
+        });
+
+        let event = Event{
+            values: vec![EventValue{ name: "price".to_string(), value: Int(10) }]
+        };
+        let results = pm.evaluate(&event);
+        assert!(results.contains(&PredResult{ id: exchange_id, result: Some(true) }));
+        assert!(results.contains(&PredResult{ id: price_id, result: Some(true) }));
+
+        let event = Event{
+            values: vec![EventValue{ name: "exchange".to_string(), value: Value::String("Y".to_string()) }]
+        };
+        assert_eq!(vec![PredResult{ id: exchange_id, result: Some(false) }], pm.evaluate(&event));
+
+        pm.clear_context();
+        assert!(pm.evaluate(&Event{ values: vec![] }).is_empty());
+    }
+
+    #[test]
+    fn fallback_chains_resolve_in_precedence_order(){
+        let mut pm = PredicateStore::new();
+        let id = pm.add("geo.country".to_string(), predicates::equal(Value::String("DE".to_string()))).unwrap();
+        pm.fallback("geo.country", &["device.geo.country", "user.geo.country"]);
+
+        let country = |name: &str, country: &str| EventValue{ name: name.to_string(), value: Value::String(country.to_string()) };
+        let evaluate = |pm: &PredicateStore, values: Vec<EventValue>| pm.evaluate(&Event{ values });
+
+        assert_eq!(vec![PredResult{ id, result: Some(true) }], evaluate(&pm, vec![country("device.geo.country", "DE")]));
+        assert_eq!(vec![PredResult{ id, result: Some(true) }], evaluate(&pm, vec![country("user.geo.country", "DE")]));
+        // The first attribute of the chain wins over later ones.
+        assert_eq!(
+            vec![PredResult{ id, result: Some(false) }],
+            evaluate(&pm, vec![country("user.geo.country", "DE"), country("device.geo.country", "FR")])
+        );
+        // The attribute itself wins over the whole chain.
+        assert_eq!(
+            vec![PredResult{ id, result: Some(true) }],
+            evaluate(&pm, vec![country("device.geo.country", "FR"), country("geo.country", "DE")])
+        );
+        assert!(evaluate(&pm, vec![country("other.geo.country", "DE")]).is_empty());
+        assert!(evaluate(&pm, vec![]).is_empty());
+
+        pm.set_context(Event{ values: vec![country("user.geo.country", "DE")] });
+        assert_eq!(vec![PredResult{ id, result: Some(true) }], evaluate(&pm, vec![]));
+        assert_eq!(vec![PredResult{ id, result: Some(false) }], evaluate(&pm, vec![country("device.geo.country", "FR")]));
+
+        pm.clear_context();
+        pm.fallback("geo.country", &["user.geo.country"]);
+        assert!(evaluate(&pm, vec![country("device.geo.country", "DE")]).is_empty());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn sampling_is_deterministic_and_close_to_rate(){
+        let mut tree = ATree::new();
+        for id in ["sampled", "always"] {
+            let mut leaf = NodeType::new_leaf(LeafNode::new(1 << 32));
+            let mut leaf_two = NodeType::new_leaf(LeafNode::new(2 << 32));
+            let mut root = NodeType::new_root(RootNode::and(id.to_string()));
+            add_children(&mut root, &mut leaf);
+            add_children(&mut root, &mut leaf_two);
+            tree.insert(root).unwrap();
+        }
+        tree.set_sample_rate("sampled", 0.5);
+
+        let results = [PredResult{ id: 1 << 32, result: Some(true) }, PredResult{ id: 2 << 32, result: Some(true) }];
+        let mut passed = 0;
+        for nonce in 0..10_000 {
+            let matched = tree.matches_sampled(&results, nonce);
+            assert!(matched.contains("always"));
+            if matched.contains("sampled") {
+                passed += 1;
+            }
+            assert_eq!(matched, tree.matches_sampled(&results, nonce));
+        }
+        assert!((4_700..=5_300).contains(&passed), "{} of 10000 passed", passed);
+
+        tree.set_sample_rate("sampled", 0.0);
+        assert!(!tree.matches_sampled(&results, 7).contains("sampled"));
+        tree.set_sample_rate("sampled", 1.0);
+        assert_eq!(2, tree.matches_sampled(&results, 7).len());
+    }
+
+    #[test]
+    fn match_options_combine_independently(){
+        let (mut tree, mut results) = wide_tree(50);
+        tree.set_clock(Arc::new(ManualClock::new(0)));
+        results[0].result = None;
+        tree.set_sample_rate("1", 0.0);
+
+        let outcome = tree.match_with(&results, &MatchOptions::default());
+        assert!(outcome.matched.contains("0"));
+        assert!(outcome.matched.contains("1"));
+
+        let outcome = tree.match_with(&results, &MatchOptions::default().strict(true));
+        assert!(outcome.resolved_false.contains("0"));
+
+        let options = MatchOptions::default()
+            .strict(true)
+            .sample_nonce(7)
+            .deadline(1);
+        let outcome = tree.match_with(&results, &options);
+        assert!(!outcome.truncated);
+        assert!(outcome.resolved_false.contains("0"));
+        assert!(!outcome.matched.contains("1"));
+        assert_eq!(48, outcome.matched.len());
+
+        let outcome = tree.match_with(&results, &options.deadline(0));
+        assert!(outcome.truncated);
+        assert!(outcome.matched.is_empty());
+        assert_eq!(50, tree.matches(&results).len());
+    }
+
+    #[test]
+    fn matching_anomalies_reach_the_warning_sink(){
+        let mut tree = ATree::new();
+        tree.insert(and_of_any_and_extra("1", &[1, 2])).unwrap();
+        let sink = Arc::new(warning::VecSink::new());
+        tree.set_warning_sink(sink.clone());
+
+        let inner_id = *tree.hash_to_node.iter()
+            .find(|(_, index)| !matches!(tree.nodes[**index].kind, SlotKind::Leaf))
+            .unwrap().0;
+        let unknown_id = leaf_id(7);
+
+        assert!(tree.matches(&[PredResult{ id: leaf_id(1), result: Some(true) }, PredResult{ id: leaf_id(101), result: Some(true) }]).contains("1"));
+        assert!(sink.take().is_empty());
+
+        tree.matches(&[
+            PredResult{ id: leaf_id(1), result: Some(true) },
+            PredResult{ id: leaf_id(1), result: Some(true) },
+            PredResult{ id: leaf_id(101), result: Some(true) },
+            PredResult{ id: leaf_id(101), result: Some(false) },
+            PredResult{ id: unknown_id, result: Some(true) },
+            PredResult{ id: inner_id, result: Some(true) },
+            PredResult{ id: unknown_id, result: None },
+        ]);
+        assert_eq!(vec![
+            Warning::DuplicateResult { id: leaf_id(1), conflicting: false },
+            Warning::DuplicateResult { id: leaf_id(101), conflicting: true },
+            Warning::UnknownPredicate { id: unknown_id },
+            Warning::NotALeaf { id: inner_id },
+        ], sink.take());
+    }
+
+    #[test]
+    fn event_ids_increase_and_respect_supplied_ids(){
+        let (tree, results) = wide_tree(2);
+        assert_eq!(None, tree.last_event_id());
+
+        let ids: Vec<u64> = (0..3).map(|_| tree.matches_with_outcome(&results).event_id).collect();
+        assert_eq!(vec![0, 1, 2], ids);
+        assert_eq!(Some(2), tree.last_event_id());
+
+        let outcome = tree.match_with(&results, &MatchOptions::default().event_id(100));
+        assert_eq!(100, outcome.event_id);
+        assert_eq!(Some(100), tree.last_event_id());
+        assert_eq!(101, tree.matches_with_outcome(&results).event_id);
+
+        assert_eq!(5, tree.match_with(&results, &MatchOptions::default().event_id(5)).event_id);
+        assert_eq!(102, tree.matches_with_outcome(&results).event_id);
+
+        assert_eq!(0, ATree::new().matches_with_outcome(&results).event_id);
+    }
+
+    #[test]
+    fn event_ids_stop_at_the_end_of_the_range(){
+        let (tree, results) = wide_tree(2);
+        assert_eq!(u64::MAX - 1, tree.match_with(&results, &MatchOptions::default().event_id(u64::MAX - 1)).event_id);
+        assert_eq!(u64::MAX, tree.matches_with_outcome(&results).event_id);
+        assert_eq!(u64::MAX, tree.matches_with_outcome(&results).event_id);
+        assert_eq!(Some(u64::MAX), tree.last_event_id());
+
+        let (tree, results) = wide_tree(2);
+        assert_eq!(u64::MAX, tree.match_with(&results, &MatchOptions::default().event_id(u64::MAX)).event_id);
+        assert_eq!(u64::MAX, tree.matches_with_outcome(&results).event_id);
+        assert_eq!(3, tree.match_with(&results, &MatchOptions::default().event_id(3)).event_id);
+        assert_eq!(u64::MAX, tree.matches_with_outcome(&results).event_id);
+    }
+
+    #[test]
+    fn result_limit_keeps_n_and_signals_overflow(){
+        let (tree, results) = wide_tree(100);
+        let all = tree.matches(&results);
+        assert_eq!(100, all.len());
+
+        let outcome = tree.match_with(&results, &MatchOptions::default().max_results(10));
+        assert_eq!(10, outcome.matched.len());
+        assert!(outcome.matched.is_subset(&all));
+        assert!(outcome.overflowed);
+        assert_eq!(100, outcome.total_matched);
+
+        let outcome = tree.match_with(&results, &MatchOptions::default().max_results(10).stop_on_limit(true));
+        assert_eq!(10, outcome.matched.len());
+        assert!(outcome.matched.is_subset(&all));
+        assert!(outcome.overflowed);
+        assert_eq!(11, outcome.total_matched);
+        assert_eq!(all, tree.matches(&results));
+
+        let outcome = tree.match_with(&results, &MatchOptions::default().max_results(100).stop_on_limit(true));
+        assert_eq!(all, outcome.matched);
+        assert!(!outcome.overflowed);
+        assert_eq!(100, outcome.total_matched);
+    }
+
+    #[test]
+    fn level_stats_count_each_level(){
+        let mut tree = ATree::new();
+        tree.insert(expression("e", And, &mut [or_of(&[1, 2]), leaf(3)])).unwrap();
+        let results = [
+            PredResult{ id: leaf_id(1), result: Some(true) },
+            PredResult{ id: leaf_id(2), result: Some(true) },
+            PredResult{ id: leaf_id(2), result: Some(false) },
+            PredResult{ id: leaf_id(3), result: Some(false) },
+        ];
+
+        assert!(tree.matches_with_outcome(&results).level_breakdown.is_empty());
+
+        let outcome = tree.match_with(&results, &MatchOptions::default().collect_level_stats(true));
+        assert_eq!(vec![
+            LevelStats{ level: 1, enqueued: 3, evaluated: 3, resolved_true: 1, resolved_false: 1, unknown: 1, deliveries: 2, short_circuited: 0 },
+            // Leaf 3 already made the root false, so the OR isn't needed.
+            LevelStats{ level: 2, enqueued: 1, evaluated: 0, resolved_true: 0, resolved_false: 0, unknown: 0, deliveries: 0, short_circuited: 1 },
+            LevelStats{ level: 3, enqueued: 1, evaluated: 1, resolved_true: 0, resolved_false: 1, unknown: 0, deliveries: 0, short_circuited: 0 },
+        ], outcome.level_breakdown);
+        assert_eq!(HashSet::from(["e".to_string()]), outcome.resolved_false);
+        assert_eq!(
+            "level 1: enqueued 3, evaluated 3, true 1, false 1, unknown 1, deliveries 2, short-circuited 0",
+            outcome.level_breakdown[0].to_string()
+        );
+    }
+
+    #[test]
+    fn decided_parents_short_circuit_their_children(){
         let mut tree = ATree::new();
-        {
-            let mut leaf = NodeType::new_leaf(LeafNode::new(1));
-            let mut leaf_two = NodeType::new_leaf(LeafNode::new(2));
+        tree.insert(expression("e", And, &mut [leaf(1), or_of(&[2, 3]), or_of(&[4, 5])])).unwrap();
+        // Leaves are taken from the back, so 1 decides the root first.
+        let results = [(2, true), (3, true), (4, false), (5, false), (1, false)]
+            .map(|(n, result)| PredResult::new(leaf_id(n), Some(result)));
+
+        let outcome = tree.match_with(&results, &MatchOptions::default().collect_level_stats(true));
+        assert_eq!(HashSet::from(["e".to_string()]), outcome.resolved_false);
+        assert_eq!(vec![4, 0, 1], outcome.level_breakdown.iter().map(|level| level.evaluated).collect::<Vec<_>>());
+        // Leaf 2 after 3 made their OR true, then both ORs after 1 made the
+        // root false.
+        assert_eq!(vec![1, 2, 0], outcome.level_breakdown.iter().map(|level| level.short_circuited).collect::<Vec<_>>());
+        assert_eq!(4, outcome.level_breakdown[0].deliveries);
+    }
 
-            let mut root = NodeType::new_root(RootNode::and("1".to_string()));
-            add_children(&mut root, &mut leaf);
-            add_children(&mut root, &mut leaf_two);
+    /// What the matcher should find, evaluated bottom-up without skipping
+    /// anything. Unknown operands are left out, as in the matcher.
+    fn reference_result(tree: &ATree, index: NodeId, results: &HashMap<u64, bool>) -> Option<bool>{
+        let node = &tree.nodes[index];
+        if let SlotKind::Leaf = node.kind {
+            return results.get(&node.id).copied();
+        }
+        let mut operands = node.children.iter()
+            .filter_map(|child| reference_result(tree, *child, results))
+            .peekable();
+        operands.peek()?;
+        match node.log_operation() {
+            Some(And) => Some(operands.all(|operand| operand)),
+            _ => Some(operands.any(|operand| operand))
+        }
+    }
 
-            tree.insert(root.clone());
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn short_circuiting_keeps_results(){
+        let mut rng = XorShift(0x5EED_0FC1_C017_ED00);
+        let mut skipped = 0;
+        for _ in 0..200 {
+            let leaf_ids: Vec<u64> = (0..6).map(|_| rng.next() | 1 << 40).collect();
+            let tree = random_tree(&mut rng, &leaf_ids);
+            let mut known = HashMap::new();
+            for id in &leaf_ids {
+                if rng.below(4) != 0 {
+                    known.insert(*id, rng.below(2) == 0);
+                }
+            }
+            let results: Vec<PredResult> = known.iter().map(|(id, result)| PredResult::new(*id, Some(*result))).collect();
+
+            let mut expected = HashSet::new();
+            for (_, node) in tree.nodes.iter() {
+                if let SlotKind::Root{ ids, .. } = &node.kind {
+                    let index = tree.hash_to_node[&node.id];
+                    if reference_result(&tree, index, &known) == Some(true) {
+                        expected.extend(ids.iter().cloned());
+                    }
+                }
+            }
+            let outcome = tree.match_with(&results, &MatchOptions::default().collect_level_stats(true));
+            assert_eq!(expected, outcome.matched);
+            skipped += outcome.level_breakdown.iter().map(|level| level.short_circuited).sum::<usize>();
         }
+        assert!(skipped > 0);
+    }
 
-        assert_eq!(3, tree.len());
-        assert_eq!(2, tree.get_m());
+    /// Cancels `token` the `calls`-th time the tree asks for the time, to
+    /// cancel at a known point in the middle of a match.
+    struct CancelOnCall{
+        calls: AtomicUsize,
+        token: CancelToken
+    }
+
+    impl Clock for CancelOnCall{
+        fn now_millis(&self) -> i64 {
+            if self.calls.fetch_sub(1, Ordering::Relaxed) == 1 {
+                self.token.cancel();
+            }
+            0
+        }
     }
 
     #[test]
-    fn insert_two_same_root_nodes(){
-        let mut tree = ATree::new();
-        {
-            let mut leaf = NodeType::new_leaf(LeafNode::new(1));
-            let mut leaf_two = NodeType::new_leaf(LeafNode::new(2));
+    fn cancel_token_stops_mid_match_and_leaves_tree_clean(){
+        let (mut tree, results) = wide_tree(200);
+        let token = CancelToken::new();
+        tree.set_clock(Arc::new(CancelOnCall{ calls: AtomicUsize::new(3), token: token.clone() }));
+        tree.set_deadline_check_interval(16);
+
+        let options = MatchOptions::default().deadline(1).cancel_token(token.clone());
+        let outcome = tree.match_with(&results, &options);
+        assert!(outcome.cancelled);
+        assert!(!outcome.truncated);
+        assert!(outcome.matched.len() < 200);
+
+        assert!(tree.match_with(&results, &options).cancelled);
+        assert_eq!(200, tree.matches(&results).len());
+    }
 
-            let mut inner = NodeType::new_inner(InnerNode::and());
-            add_children(&mut inner, &mut leaf);
-            add_children(&mut inner, &mut leaf_two);
+    #[cfg(feature = "std")]
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn cancel_token_works_across_threads(){
+        let (mut tree, results) = wide_tree(500);
+        tree.set_deadline_check_interval(1);
+        let token = CancelToken::new();
+        let options = MatchOptions::default().cancel_token(token.clone());
+
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(5));
+            token.cancel();
+        });
+        let mut rounds = 0;
+        while !tree.match_with(&results, &options).cancelled {
+            rounds += 1;
+            assert!(rounds < 1_000_000, "cancellation never observed");
+        }
+        canceller.join().unwrap();
+        assert_eq!(500, tree.matches(&results).len());
+    }
 
-            let mut root = NodeType::new_root(RootNode::and("1".to_string()));
-            add_children(&mut root,&mut inner);
+    #[cfg(feature = "std")]
+    #[test]
+    fn shared_tree_matches_from_many_threads(){
+        fn assert_send_sync<T: Send + Sync>(){}
+        assert_send_sync::<ATree>();
+
+        let mut rng = XorShift(0x0DDB_1A5E_5BAD_5EED);
+        let leaf_ids: Vec<u64> = (0..6).map(|_| rng.next() | 1 << 40).collect();
+        let tree = random_tree(&mut rng, &leaf_ids);
+        let batches: Vec<Vec<PredResult>> = (0..8)
+            .map(|_| leaf_ids.iter().map(|id| PredResult::new(*id, Some(rng.below(2) == 0))).collect())
+            .collect();
+        let expected: Vec<HashSet<String>> = batches.iter().map(|batch| tree.matches(batch)).collect();
+        assert!(expected.iter().any(|matched| !matched.is_empty()));
+
+        let tree = Arc::new(tree);
+        let threads: Vec<_> = batches.into_iter().map(|batch| {
+            let tree = tree.clone();
+            std::thread::spawn(move || (0..200).map(|_| tree.matches(&batch)).collect::<Vec<_>>())
+        }).collect();
+        for (thread, expected) in threads.into_iter().zip(expected) {
+            for matched in thread.join().unwrap() {
+                assert_eq!(expected, matched);
+            }
+        }
+    }
 
-            tree.insert(root.clone());
+    struct FixedId(u64);
+
+    impl Predicate for FixedId{
+        fn id(&self) -> u64 {
+            self.0
         }
 
-        {
-            let mut leaf = NodeType::new_leaf(LeafNode::new(1));
-            let mut leaf_two = NodeType::new_leaf(LeafNode::new(2));
+        fn evaluate(&self, _: &Value) -> bool {
+            true
+        }
+    }
 
-            let mut inner = NodeType::new_inner(InnerNode::and());
+    #[test]
+    fn different_rules_on_one_attribute_are_all_kept(){
+        let mut pm = PredicateStore::new();
+        let ids = [
+            pm.add("price".to_string(), predicates::equal(Int(10))).unwrap(),
+            pm.add("price".to_string(), predicates::greater(Int(10))).unwrap(),
+            pm.add("price".to_string(), predicates::element_of(vec![Int(10)])).unwrap(),
+            pm.add("price".to_string(), predicates::not_element_of(vec![Int(10)])).unwrap(),
+        ];
+        for (i, id) in ids.iter().enumerate() {
+            assert!(!ids[..i].contains(id), "rule {} reuses an id", i);
+        }
+
+        let results = pm.evaluate(&Event{ values: vec![EventValue{ name: "price".to_string(), value: Int(10) }] });
+        let expected = [Some(true), Some(false), Some(true), Some(false)];
+        for (id, result) in ids.iter().zip(expected) {
+            assert!(results.contains(&PredResult{ id: *id, result }));
+        }
+    }
+
+    #[test]
+    fn same_constant_on_different_attributes_gets_distinct_ids(){
+        let mut pm = PredicateStore::new();
+        let price_id = pm.add("price".to_string(), predicates::equal(Int(10))).unwrap();
+        let bid_id = pm.add("bid".to_string(), predicates::equal(Int(10))).unwrap();
+        assert_ne!(price_id, bid_id);
+        assert_eq!(Ok(price_id), pm.add("price".to_string(), predicates::equal(Int(10))));
+        assert_eq!(Some(PredicateKindTag::Equal), predicate_kind_of(bid_id));
+
+        let event = Event{
+            values: vec![
+                EventValue{ name: "price".to_string(), value: Int(10) },
+                EventValue{ name: "bid".to_string(), value: Int(11) },
+            ]
+        };
+        let results = pm.evaluate(&event);
+        assert_eq!(2, results.len());
+        assert!(results.contains(&PredResult{ id: price_id, result: Some(true) }));
+        assert!(results.contains(&PredResult{ id: bid_id, result: Some(false) }));
+    }
+
+    #[test]
+    fn id_owned_by_another_attribute_is_rejected(){
+        let mut pm = PredicateStore::new();
+        let price_id = pm.add("price".to_string(), FixedId(1)).unwrap();
+
+        let colliding = FixedId(price_id ^ attribute_id(Namespace::Attribute, "bid", 0));
+        assert_eq!(
+            Err(PredicateError::AlreadyRegistered { other_attribute: "price".to_string() }),
+            pm.add("bid".to_string(), colliding)
+        );
+        assert_eq!(1, pm.evaluate(&Event{ values: vec![EventValue{ name: "price".to_string(), value: Int(1) }] }).len());
+        assert!(pm.evaluate(&Event{ values: vec![EventValue{ name: "bid".to_string(), value: Int(1) }] }).is_empty());
+    }
+
+    /// `AND(OR(leaves), extra)`, where the extra leaf is predicate
+    /// `leaves[0] + 100`. True once any of `leaves` and the extra one are.
+    fn and_of_any_and_extra(id: &str, leaves: &[u64]) -> ArcNodeLink{
+        let mut root = NodeType::new_root(RootNode::and(id.to_string()));
+        let mut inner = NodeType::new_inner(InnerNode::or());
+        for leaf in leaves {
+            let mut leaf = NodeType::new_leaf(LeafNode::new(leaf_id(*leaf)));
             add_children(&mut inner, &mut leaf);
-            add_children(&mut inner, &mut leaf_two);
+        }
+        let mut leaf = NodeType::new_leaf(LeafNode::new(leaf_id(leaves[0] + 100)));
+        add_children(&mut root, &mut inner);
+        add_children(&mut root, &mut leaf);
+        root
+    }
 
-            let mut root = NodeType::new_root(RootNode::and("1".to_string()));
-            add_children(&mut root,&mut inner);
+    fn expression(id: &str, operation: LogOperation, children: &mut [ArcNodeLink]) -> ArcNodeLink{
+        let mut root = NodeType::new_root(RootNode::new(id.to_string(), operation));
+        for children in children {
+            add_children(&mut root, children);
+        }
+        root
+    }
+
+    /// Predicate `n` of the tests. Ids are scrambled so sums and products of
+    /// leaf ids don't land on other leaves' ids.
+    fn leaf_id(n: u64) -> u64{
+        let id = n.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+        (id ^ id >> 29).wrapping_mul(0xBF58_476D_1CE4_E5B9)
+    }
+
+    fn leaf(n: u64) -> ArcNodeLink{
+        NodeType::new_leaf(LeafNode::new(leaf_id(n)))
+    }
 
-            tree.insert(root.clone());
+    fn or_of(leaves: &[u64]) -> ArcNodeLink{
+        let mut inner = NodeType::new_inner(InnerNode::or());
+        for n in leaves {
+            add_children(&mut inner, &mut leaf(*n));
         }
+        inner
+    }
 
-        assert_eq!(3, tree.len());
-        assert_eq!(3, tree.get_m());
+    fn not_of(mut child: ArcNodeLink) -> ArcNodeLink{
+        let mut not = NodeType::new_inner(InnerNode::not());
+        add_children(&mut not, &mut child);
+        not
     }
 
     #[test]
-    fn insert_two_dif_root_nodes(){
+    fn not_negates_a_leaf_and_an_or_subtree(){
         let mut tree = ATree::new();
-        {
-            let mut leaf = NodeType::new_leaf(LeafNode::new(4));
-            let mut leaf_two = NodeType::new_leaf(LeafNode::new(6));
+        // 1 AND NOT 2
+        tree.insert(expression("leaf", And, &mut [leaf(1), not_of(leaf(2))])).unwrap();
+        // 1 AND NOT (3 OR 4)
+        tree.insert(expression("or", And, &mut [leaf(1), not_of(or_of(&[3, 4]))])).unwrap();
+
+        let matches = |results: &[(u64, Option<bool>)]| {
+            let results: Vec<PredResult> = results.iter().map(|(n, result)| PredResult::new(leaf_id(*n), *result)).collect();
+            tree.matches_with_outcome(&results)
+        };
 
-            let mut inner = NodeType::new_inner(InnerNode::and());
-            add_children(&mut inner, &mut leaf);
-            add_children(&mut inner, &mut leaf_two);
+        let outcome = matches(&[(1, Some(true)), (2, Some(false)), (3, Some(false)), (4, Some(false))]);
+        assert_eq!(HashSet::from(["leaf".to_string(), "or".to_string()]), outcome.matched);
 
-            let mut root = NodeType::new_root(RootNode::and("1".to_string()));
-            add_children(&mut root,&mut inner);
+        let outcome = matches(&[(1, Some(true)), (2, Some(true)), (3, Some(false)), (4, Some(true))]);
+        assert!(outcome.matched.is_empty());
+        assert_eq!(HashSet::from(["leaf".to_string(), "or".to_string()]), outcome.resolved_false);
+
+        // An unknown operand stays unknown through the negation rather than
+        // turning true.
+        let mut tree = ATree::new();
+        tree.insert(expression("either", Or, &mut [leaf(5), not_of(leaf(2))])).unwrap();
+        let outcome = tree.matches_with_outcome(&[PredResult::new(leaf_id(5), Some(false)), PredResult::new(leaf_id(2), None)]);
+        assert!(outcome.matched.is_empty());
+        assert!(outcome.resolved_false.contains("either"));
+    }
+
+    #[test]
+    fn not_gets_its_own_id_and_a_single_child(){
+        let id = |node: ArcNodeLink| node.borrow().get_id();
+        let operations = [InnerNode::and(), InnerNode::or(), InnerNode::not()];
+        let ids: Vec<u64> = operations.map(|operation| {
+            let mut inner = NodeType::new_inner(operation);
+            add_children(&mut inner, &mut leaf(1));
+            id(inner)
+        }).to_vec();
+        assert_eq!(ids[0], ids[1]);
+        assert_ne!(ids[0], ids[2]);
+        assert_ne!(ids[0], id(leaf(1)));
+        assert_ne!(id(not_of(not_of(leaf(1)))), id(leaf(1)));
+
+        let mut tree = ATree::new();
+        let mut not = NodeType::new_inner(InnerNode::not());
+        add_children(&mut not, &mut leaf(1));
+        add_children(&mut not, &mut leaf(2));
+        assert_eq!(
+            Err(ATreeError::NotArity { node_id: 2, children: 2 }),
+            tree.insert(expression("e", And, &mut [leaf(3), not])).map(|_| ())
+        );
+        let empty = NodeType::new_inner(InnerNode::not());
+        assert_eq!(
+            Err(ATreeError::NotArity { node_id: 1, children: 0 }),
+            tree.insert(expression("e", And, &mut [empty, leaf(3)])).map(|_| ())
+        );
+        assert!(tree.is_empty());
+    }
 
-            tree.insert(root.clone());
+    #[test]
+    fn at_least_matches_two_of_four(){
+        let leaves = || [1, 2, 3, 4].map(leaf);
+        let mut tree = ATree::new();
+        tree.insert(expression("two", AtLeast(2), &mut leaves())).unwrap();
+        tree.insert(expression("three", AtLeast(3), &mut leaves())).unwrap();
+        let mut two_of_three = NodeType::new_inner(InnerNode::at_least(2));
+        for mut child in [1, 2, 3].map(leaf) {
+            add_children(&mut two_of_three, &mut child);
         }
+        tree.insert(expression("nested", Or, &mut [leaf(5), two_of_three])).unwrap();
+        // Five leaves, the two thresholds and the nested root and threshold.
+        assert_eq!(9, tree.len());
 
-        {
-            let mut leaf = NodeType::new_leaf(LeafNode::new(8));
-            let mut leaf_two = NodeType::new_leaf(LeafNode::new(2));
+        let matches = |results: &[(u64, Option<bool>)]| {
+            let results: Vec<PredResult> = results.iter().map(|(n, result)| PredResult::new(leaf_id(*n), *result)).collect();
+            tree.matches_with_outcome(&results)
+        };
 
-            let mut inner = NodeType::new_inner(InnerNode::or());
-            add_children(&mut inner, &mut leaf);
-            add_children(&mut inner, &mut leaf_two);
+        let outcome = matches(&[(1, Some(true)), (2, Some(false)), (3, None), (4, Some(true)), (5, Some(false))]);
+        assert_eq!(HashSet::from(["two".to_string()]), outcome.matched);
+        // Three could still get its third true from the unknown operand. The
+        // nested threshold stays unknown too, leaving its OR with the false 5.
+        assert_eq!(HashSet::from(["nested".to_string()]), outcome.resolved_false);
 
-            let mut root = NodeType::new_root(RootNode::and("1".to_string()));
-            add_children(&mut root,&mut inner);
+        // Two false out of four still leaves room for two true.
+        let outcome = matches(&[(1, Some(false)), (2, Some(true)), (3, Some(false)), (4, None), (5, Some(false))]);
+        assert!(outcome.matched.is_empty());
+        assert_eq!(HashSet::from(["three".to_string(), "nested".to_string()]), outcome.resolved_false);
+
+        let outcome = matches(&[(1, Some(false)), (2, Some(false)), (3, Some(false)), (4, Some(true)), (5, Some(false))]);
+        assert!(outcome.matched.is_empty());
+        assert_eq!(HashSet::from(["two".to_string(), "three".to_string(), "nested".to_string()]), outcome.resolved_false);
+
+        let outcome = matches(&[(1, Some(true)), (2, Some(true)), (3, Some(true)), (4, Some(false)), (5, Some(false))]);
+        assert_eq!(HashSet::from(["two".to_string(), "three".to_string(), "nested".to_string()]), outcome.matched);
+
+        tree.insert(expression("two again", AtLeast(2), &mut leaves())).unwrap();
+        assert_eq!(9, tree.len());
+        assert!(tree.matches(&[PredResult::new(leaf_id(1), Some(true)), PredResult::new(leaf_id(3), Some(true))]).contains("two again"));
+    }
+
+    #[test]
+    fn find_subsumed_reports_conjunct_supersets(){
+        let mut tree = ATree::new();
+        tree.insert(expression("price", And, &mut [leaf(1), leaf(9)])).unwrap();
+        tree.insert(expression("price_and_country", And, &mut [leaf(1), leaf(9), leaf(2)])).unwrap();
+        tree.insert(expression("price_and_country_again", And, &mut [leaf(2), leaf(1), leaf(9)])).unwrap();
+        tree.insert(expression("country_and_city", And, &mut [leaf(2), leaf(3)])).unwrap();
+
+        assert_eq!(vec![
+            ("price_and_country".to_string(), "price".to_string()),
+            ("price_and_country_again".to_string(), "price".to_string()),
+            ("price_and_country_again".to_string(), "price_and_country".to_string()),
+        ], tree.find_subsumed());
+    }
+
+    #[test]
+    fn subsumed_pairs_assume_every_predicate_is_reported(){
+        let mut tree = ATree::new();
+        tree.insert(expression("price", And, &mut [leaf(1), leaf(9)])).unwrap();
+        tree.insert(expression("price_and_country", And, &mut [leaf(1), leaf(9), leaf(2)])).unwrap();
+        assert_eq!(vec![("price_and_country".to_string(), "price".to_string())], tree.find_subsumed());
+
+        let all = [1, 9, 2].map(|n| PredResult::new(leaf_id(n), Some(true)));
+        assert_eq!(HashSet::from(["price".to_string(), "price_and_country".to_string()]), tree.matches(&all));
+
+        // Without results for 1 and 9 the narrower expression is judged on 2
+        // alone, and the pair doesn't hold.
+        let country_only = [PredResult::new(leaf_id(2), Some(true))];
+        assert_eq!(HashSet::from(["price_and_country".to_string()]), tree.matches(&country_only));
+    }
+
+    #[test]
+    fn find_subsumed_handles_or_branches(){
+        let mut tree = ATree::new();
+        tree.insert(expression("any_of_three", Or, &mut [leaf(1), leaf(2), leaf(3)])).unwrap();
+        tree.insert(expression("one_of_two", And, &mut [or_of(&[1, 2]), leaf(4)])).unwrap();
+        tree.insert(expression("one_of_four", And, &mut [or_of(&[1, 5]), leaf(4)])).unwrap();
+
+        assert_eq!(vec![("one_of_two".to_string(), "any_of_three".to_string())], tree.find_subsumed());
+    }
+
+    #[test]
+    fn find_subsumed_is_empty_for_unrelated_expressions(){
+        let mut tree = ATree::new();
+        tree.insert(expression("a", And, &mut [leaf(1), leaf(2)])).unwrap();
+        tree.insert(expression("b", And, &mut [leaf(1), leaf(3)])).unwrap();
+        tree.insert(expression("c", Or, &mut [leaf(4), leaf(5)])).unwrap();
+
+        assert!(tree.find_subsumed().is_empty());
+        assert!(ATree::new().find_subsumed().is_empty());
+    }
+
+    #[test]
+    fn plan_insert_matches_real_insert(){
+        let mut tree = ATree::new();
+        tree.insert(and_of_any_and_extra("1", &[1, 2])).unwrap();
+        tree.insert(and_of_any_and_extra("2", &[3, 4])).unwrap();
+        let len = tree.len();
+
+        let batch = vec![
+            and_of_any_and_extra("1", &[1, 2]),
+            and_of_any_and_extra("2", &[3, 4]),
+            and_of_any_and_extra("3", &[1, 5]),
+            and_of_any_and_extra("4", &[1, 5]),
+        ];
+        let plan = tree.plan_insert(&batch);
+        assert_eq!(len, tree.len());
+
+        assert_eq!(ExpressionPlan{ would_create_nodes: 0, would_share_nodes: 1, validation_issues: vec![] }, plan.expressions[0]);
+        assert_eq!(ExpressionPlan{ would_create_nodes: 0, would_share_nodes: 1, validation_issues: vec![] }, plan.expressions[1]);
+        assert_eq!(ExpressionPlan{ would_create_nodes: 3, would_share_nodes: 2, validation_issues: vec![] }, plan.expressions[2]);
+        assert_eq!(ExpressionPlan{ would_create_nodes: 0, would_share_nodes: 1, validation_issues: vec![] }, plan.expressions[3]);
+        assert_eq!(3, plan.would_create_nodes);
+        assert_eq!(5, plan.would_share_nodes);
+
+        for node in batch {
+            tree.insert(node).unwrap();
+        }
+        assert_eq!(len + plan.would_create_nodes, tree.len());
+    }
+
+    #[test]
+    fn plan_insert_reports_cycles(){
+        let tree = ATree::new();
+        let mut inner = NodeType::new_inner(InnerNode::and());
+        let mut same = inner.clone();
+        add_children(&mut inner, &mut same);
+        let mut root = NodeType::new_root(RootNode::and("1".to_string()));
+        add_children(&mut root, &mut inner);
+
+        let plan = tree.plan_insert(&[root, and_of_any_and_extra("2", &[1, 2])]);
+        assert_eq!(vec![ATreeError::CycleDetected { node_id: 1 }], plan.expressions[0].validation_issues);
+        assert_eq!(0, plan.expressions[0].would_create_nodes);
+        assert_eq!(5, plan.would_create_nodes);
+    }
+
+    #[test]
+    fn empty_tree_with_results_matches_nothing(){
+        let tree = ATree::new();
+        let results = [PredResult{ id: 1, result: Some(true) }, PredResult{ id: 2, result: None }];
+
+        assert_eq!(MatchOutcome::default(), tree.matches_with_outcome(&results));
+        assert!(tree.matches(&[]).is_empty());
+    }
+
+    #[test]
+    fn populated_tree_with_no_results_matches_nothing(){
+        let mut tree = ATree::new();
+        tree.insert(and_of_any_and_extra("1", &[1, 2])).unwrap();
+
+        assert_eq!(MatchOutcome::default(), tree.matches_with_outcome(&[]));
+    }
+
+    #[test]
+    fn empty_event_evaluates_to_no_results(){
+        let mut pm = PredicateStore::new();
+        let mut tree = ATree::new();
+        let id = pm.add("A1".to_string(), predicates::equal(Int(10))).unwrap();
+        let mut leaf = NodeType::new_leaf(LeafNode::new(id));
+        let mut root = NodeType::new_root(RootNode::or("1".to_string()));
+        add_children(&mut root, &mut leaf);
+        tree.insert(root).unwrap();
+
+        let results = pm.evaluate(&Event{ values: vec![] });
+        assert!(results.is_empty());
+        assert!(tree.matches(&results).is_empty());
+    }
+
+    #[test]
+    fn remove_keeps_nodes_other_expressions_share(){
+        let mut tree = ATree::new();
+        tree.insert(and_of_any_and_extra("1", &[1, 2])).unwrap();
+        tree.insert(and_of_any_and_extra("2", &[1, 3])).unwrap();
+        tree.insert(and_of_any_and_extra("3", &[1, 3])).unwrap();
+        let len = tree.len();
+
+        // "1" owns its root, its OR node and leaf 2; leaves 1 and 101 are shared.
+        assert!(tree.remove("1"));
+        assert_eq!(len - 3, tree.len());
+        assert!(!tree.remove("1"));
+        assert!(!tree.hash_to_node.contains_key(&leaf_id(2)));
+
+        let results = [PredResult::new(leaf_id(1), Some(true)), PredResult::new(leaf_id(2), Some(true)), PredResult::new(leaf_id(101), Some(true))];
+        assert_eq!(HashSet::from_iter(["2".to_string(), "3".to_string()]), tree.matches(&results));
+
+        // "3" has the same structure as "2", so removing "2" frees nothing.
+        assert!(tree.remove("2"));
+        assert_eq!(len - 3, tree.len());
+        assert_eq!(HashSet::from_iter(["3".to_string()]), tree.matches(&results));
+
+        assert!(tree.remove("3"));
+        assert!(tree.is_empty());
+        assert!(tree.matches(&results).is_empty());
+
+        tree.insert(and_of_any_and_extra("1", &[1, 2])).unwrap();
+        assert_eq!(HashSet::from_iter(["1".to_string()]), tree.matches(&results));
+    }
+
+    #[test]
+    fn remove_detaches_repeated_children(){
+        let mut tree = ATree::new();
+        let mut inner = NodeType::new_inner(InnerNode::or());
+        add_children(&mut inner, &mut leaf(1));
+        add_children(&mut inner, &mut leaf(2));
+        let mut root = NodeType::new_root(RootNode::and("twice".to_string()));
+        add_children(&mut root, &mut inner.clone());
+        add_children(&mut root, &mut inner);
+        add_children(&mut root, &mut leaf(3));
+        tree.insert(root).unwrap();
+        tree.insert(expression("other", And, &mut [leaf(2), leaf(4)])).unwrap();
+
+        assert!(tree.remove("twice"));
+        assert_eq!(3, tree.len());
+        let results = [PredResult::new(leaf(2).borrow().get_id(), Some(true)), PredResult::new(leaf(4).borrow().get_id(), Some(true))];
+        assert!(tree.matches(&results).contains("other"));
+    }
+
+    #[test]
+    fn expressions_can_be_part_of_other_expressions(){
+        let build = |id: &str| match id {
+            "outer" => expression(id, And, &mut [or_of(&[1, 2]), leaf(3)]),
+            "inner" => expression(id, Or, &mut [leaf(1), leaf(2)]),
+            "first" => expression(id, Or, &mut [leaf(4), leaf(5)]),
+            _ => expression(id, And, &mut [or_of(&[4, 5]), leaf(6)])
+        };
+        // "inner" is a node of "outer" inserted first, "first" a node of
+        // "around" inserted after it.
+        let ids = ["outer", "inner", "first", "around"];
+        let results = [1, 3, 4, 6].map(|n| PredResult::new(leaf_id(n), Some(true)));
 
-            tree.insert(root.clone());
+        let mut tree = ATree::new();
+        for id in ids {
+            tree.insert(build(id)).unwrap();
         }
+        assert_eq!(10, tree.len());
+        assert_eq!(HashSet::from(ids.map(String::from)), tree.matches(&results));
 
+        assert!(tree.remove("inner") && tree.remove("first"));
+        assert_eq!(HashSet::from(["outer".to_string(), "around".to_string()]), tree.matches(&results));
+        assert!(tree.remove("outer") && tree.remove("around"));
+        assert!(tree.is_empty());
+
+        for id in ids {
+            tree.insert(build(id)).unwrap();
+        }
+        assert!(tree.remove("outer") && tree.remove("around"));
+        assert_eq!(HashSet::from(["inner".to_string(), "first".to_string()]), tree.matches(&results));
         assert_eq!(6, tree.len());
-        assert_eq!(3, tree.get_m());
     }
 
     #[test]
-    fn insert_two_dif_root_and_m_4_nodes(){
+    fn subscriptions_route_matches_back_to_each_insert(){
         let mut tree = ATree::new();
-        {
-            let mut leaf_one = NodeType::new_leaf(LeafNode::new(4));
-            let mut leaf_two = NodeType::new_leaf(LeafNode::new(6));
+        let first = tree.insert(and_of_any_and_extra("1", &[1, 2])).unwrap();
+        let second = tree.insert(and_of_any_and_extra("2", &[1, 3])).unwrap();
+        // Same structure as "1", shared root, separate subscriptions.
+        let third = tree.insert(and_of_any_and_extra("1", &[1, 2])).unwrap();
+        let fourth = tree.insert(and_of_any_and_extra("4", &[1, 2])).unwrap();
+        assert!(first < second && second < third && third < fourth);
+        assert_eq!(0, first.value());
+
+        let one = [PredResult::new(leaf_id(1), Some(true)), PredResult::new(leaf_id(101), Some(true))];
+        assert_eq!(vec![first, second, third, fourth], tree.matched_subscriptions(&one));
+        let two = [PredResult::new(leaf_id(1), Some(false)), PredResult::new(leaf_id(2), Some(true)), PredResult::new(leaf_id(101), Some(true))];
+        assert_eq!(vec![first, third, fourth], tree.matched_subscriptions(&two));
+        // Only the subscriptions of ids that made it under the limit are returned.
+        let limited = tree.match_with(&two, &MatchOptions::default().max_results(1));
+        let expected = if limited.matched.contains("1") { vec![first, third] } else { vec![fourth] };
+        assert_eq!(expected, limited.subscriptions);
+
+        assert!(tree.remove("1"));
+        assert_eq!(vec![fourth], tree.matched_subscriptions(&two));
+        assert_eq!(vec![fourth], tree.deep_clone().matched_subscriptions(&two));
+
+        let fifth = tree.insert(and_of_any_and_extra("5", &[1, 2])).unwrap();
+        assert!(fifth > fourth);
+    }
 
+    #[test]
+    fn clone_does_not_share_nodes(){
+        let mut tree = ATree::new();
+        tree.insert(and_of_any_and_extra("1", &[1, 2])).unwrap();
+        tree.insert(and_of_any_and_extra("2", &[1, 3])).unwrap();
+        let results = [PredResult{ id: leaf_id(1), result: Some(true) }, PredResult{ id: leaf_id(101), result: Some(true) }];
+
+        let mut copy = tree.deep_clone();
+        assert_eq!(tree.len(), copy.len());
+        assert_eq!(tree.get_m(), copy.get_m());
+        assert_eq!(tree.matches(&results), copy.matches(&results));
+
+        copy.insert(and_of_any_and_extra("3", &[4, 5])).unwrap();
+        assert!(copy.remove("1"));
+
+        assert_eq!(HashSet::from(["1".to_string(), "2".to_string()]), tree.matches(&results));
+        assert_eq!(HashSet::from(["2".to_string()]), copy.matches(&results));
+        // "3" added five nodes; "1" took its root, its OR and leaf 2 with it.
+        assert_eq!(tree.len() + 5 - 3, copy.len());
+        assert_eq!(tree.len(), tree.clone().len());
+    }
 
+    #[test]
+    fn pattern_predicates_are_existential_over_matching_attributes(){
+        let mut pm = PredicateStore::new();
+        let id = pm.add_pattern("*.geo.country", predicates::equal(Value::String("DE".to_string()))).unwrap();
+        assert_ne!(id, pm.add("device.geo.country".to_string(), predicates::equal(Value::String("DE".to_string()))).unwrap());
+        assert_eq!(Ok(id), pm.add_pattern("*.geo.country", predicates::equal(Value::String("DE".to_string()))));
 
-            let mut root_inner_1_inner_1 = NodeType::new_inner(InnerNode::and());
-            add_children(&mut root_inner_1_inner_1, &mut leaf_one);
-            add_children(&mut root_inner_1_inner_1, &mut leaf_two);
-            let mut root_inner_1_inner_2 = NodeType::new_inner(InnerNode::or());
-            add_children(&mut root_inner_1_inner_2, &mut leaf_one);
-            add_children(&mut root_inner_1_inner_2, &mut leaf_two);
+        let country = |name: &str, value: &str| EventValue{ name: name.to_string(), value: Value::String(value.to_string()) };
+        let only_pattern = |results: Vec<PredResult>| results.into_iter().filter(|r| r.id == id).collect::<Vec<_>>();
 
-            let mut root_inner_2_inner_1 = NodeType::new_inner(InnerNode::and());
-            add_children(&mut root_inner_2_inner_1, &mut leaf_one);
-            add_children(&mut root_inner_2_inner_1, &mut leaf_two);
-            let mut root_inner_2_inner_2 = NodeType::new_inner(InnerNode::and());
-            add_children(&mut root_inner_2_inner_2, &mut leaf_one);
-            add_children(&mut root_inner_2_inner_2, &mut leaf_two);
+        let event = Event{ values: vec![country("device.geo.country", "FR"), country("user.geo.country", "DE")] };
+        assert_eq!(vec![PredResult{ id, result: Some(true) }], only_pattern(pm.evaluate(&event)));
 
-            let mut root_inner_1 = NodeType::new_inner(InnerNode::and());
-            add_children(&mut root_inner_1, &mut root_inner_1_inner_1);
-            add_children(&mut root_inner_1, &mut root_inner_1_inner_2);
-            let mut root_inner_2 = NodeType::new_inner(InnerNode::and());
-            add_children(&mut root_inner_2, &mut root_inner_2_inner_1);
-            add_children(&mut root_inner_2, &mut root_inner_2_inner_2);
+        let event = Event{ values: vec![country("device.geo.country", "FR"), country("user.geo.country", "IT")] };
+        assert_eq!(vec![PredResult{ id, result: Some(false) }], only_pattern(pm.evaluate(&event)));
 
+        let event = Event{ values: vec![country("device.geoX.country", "DE"), country("geo.country", "DE")] };
+        assert!(only_pattern(pm.evaluate(&event)).is_empty());
+    }
 
-            let mut root = NodeType::new_root(RootNode::and("1".to_string()));
-            add_children(&mut root,&mut root_inner_1);
-            add_children(&mut root,&mut root_inner_2);
+    #[test]
+    fn attributes_patterns_and_pairs_do_not_share_ids(){
+        use crate::predicates::{CrossOperation, EqOperation};
+
+        let mut pm = PredicateStore::new();
+        let exact = pm.add("device.*".to_string(), predicates::equal(Int(1))).unwrap();
+        let pattern = pm.add_pattern("device.*", predicates::equal(Int(1))).unwrap();
+        assert_ne!(exact, pattern);
+        let results = pm.evaluate(&Event{ values: vec![EventValue{ name: "device.os".to_string(), value: Int(1) }] });
+        assert_eq!(vec![PredResult{ id: pattern, result: Some(true) }], results);
+
+        let pair = CrossAttrPredicate::new("a", "b", CrossOperation::Eq(EqOperation::Equal));
+        let pair_key = attribute_id(Namespace::Cross, "a,b", pair.id());
+        assert_ne!(pair_key, attribute_id(Namespace::Attribute, "a,b", pair.id()));
+        assert_ne!(pair_key, attribute_id(Namespace::Pattern, "a,b", pair.id()));
+        assert_eq!(Ok(pair_key), pm.add_cross(pair));
+    }
+
+    #[test]
+    fn single_attribute_fast_path_equals_general_path(){
+        let mut pm = PredicateStore::new();
+        for attribute in ["device.os", "device.model", "user.age", "price"] {
+            for n in 0..5 {
+                pm.add(attribute.to_string(), predicates::equal(Int(n))).unwrap();
+                pm.add(attribute.to_string(), predicates::greater(Int(n * 10))).unwrap();
+            }
+        }
+        pm.add_pattern("device.*", predicates::less(Int(3))).unwrap();
+        pm.add_pattern("*.age", predicates::equal(Int(42))).unwrap();
+
+        for attribute in ["device.os", "device.model", "user.age", "price", "unknown", "user.geo.age"] {
+            for n in [0, 3, 42, 100] {
+                let value = || EventValue{ name: attribute.to_string(), value: Int(n) };
+                let mut fast = pm.evaluate(&Event{ values: vec![value()] });
+                let mut general = pm.evaluate_values([value()].iter());
+                fast.sort_by_key(|r| r.id);
+                general.sort_by_key(|r| r.id);
+                assert_eq!(general, fast);
+            }
+        }
+    }
+
+    #[test]
+    fn cross_attribute_predicates_need_both_attributes(){
+        use crate::predicates::{CrossOperation, EqOperation, OrdOperation};
+
+        let mut pm = PredicateStore::new();
+        let floor_below_cpm = pm.add_cross(CrossAttrPredicate::new("bid_floor", "max_cpm", CrossOperation::Ord(OrdOperation::LessEqual))).unwrap();
+        let same_country = pm.add_cross(CrossAttrPredicate::new("country", "ip_country", CrossOperation::Eq(EqOperation::Equal))).unwrap();
+        assert_ne!(floor_below_cpm, same_country);
+        assert_eq!(Some(PredicateKindTag::Cross), predicate_kind_of(same_country));
+        assert_eq!(Ok(floor_below_cpm), pm.add_cross(CrossAttrPredicate::new("bid_floor", "max_cpm", CrossOperation::Ord(OrdOperation::LessEqual))));
+        assert_ne!(floor_below_cpm, pm.add_cross(CrossAttrPredicate::new("max_cpm", "bid_floor", CrossOperation::Ord(OrdOperation::LessEqual))).unwrap());
+
+        let value = |name: &str, value: Value| EventValue{ name: name.to_string(), value };
+        let result_of = |pm: &PredicateStore, values: Vec<EventValue>, id: u64| {
+            let results: Vec<PredResult> = pm.evaluate(&Event{ values }).into_iter().filter(|r| r.id == id).collect();
+            assert!(results.len() <= 1);
+            results.first().map(|r| r.result)
+        };
+
+        assert_eq!(Some(Some(true)), result_of(&pm, vec![value("bid_floor", Int(2)), value("max_cpm", Int(5))], floor_below_cpm));
+        assert_eq!(Some(Some(false)), result_of(&pm, vec![value("max_cpm", Int(1)), value("bid_floor", Int(2))], floor_below_cpm));
+        assert_eq!(Some(None), result_of(&pm, vec![value("bid_floor", Int(2))], floor_below_cpm));
+        assert_eq!(Some(None), result_of(&pm, vec![value("max_cpm", Int(2)), value("price", Int(1))], floor_below_cpm));
+        assert_eq!(None, result_of(&pm, vec![value("price", Int(2))], floor_below_cpm));
+
+        assert_eq!(Some(Some(false)), result_of(&pm, vec![value("bid_floor", Int(2)), value("max_cpm", Value::String("5".to_string()))], floor_below_cpm));
+        assert_eq!(Some(Some(false)), result_of(&pm, vec![value("bid_floor", Value::String("5".to_string())), value("max_cpm", Int(2))], floor_below_cpm));
+        assert_eq!(Some(Some(true)), result_of(&pm, vec![value("country", Value::String("DE".to_string())), value("ip_country", Value::String("DE".to_string()))], same_country));
+        assert_eq!(Some(Some(false)), result_of(&pm, vec![value("country", Int(1)), value("ip_country", Value::String("1".to_string()))], same_country));
+    }
+
+    #[test]
+    fn nan_is_unknown_and_infinities_order_normally(){
+        use crate::predicates::Double;
+
+        let d = |v: f64| Value::Double(Double::new(v));
+        type Expected = fn(f64, f64, f64) -> bool;
+        let mut pm = PredicateStore::new();
+        let mut cases: Vec<(u64, f64, f64, Expected)> = vec![];
+        for c in [1.0, f64::INFINITY, f64::NEG_INFINITY] {
+            cases.push((pm.add("x".to_string(), predicates::equal(d(c))).unwrap(), c, c, |v, c, _| v == c));
+            cases.push((pm.add("x".to_string(), predicates::not_equal(d(c))).unwrap(), c, c, |v, c, _| v != c));
+            cases.push((pm.add("x".to_string(), predicates::greater(d(c))).unwrap(), c, c, |v, c, _| v > c));
+            cases.push((pm.add("x".to_string(), predicates::greater_equal(d(c))).unwrap(), c, c, |v, c, _| v >= c));
+            cases.push((pm.add("x".to_string(), predicates::less_equal(d(c))).unwrap(), c, c, |v, c, _| v <= c));
+            cases.push((pm.add("x".to_string(), predicates::less(d(c))).unwrap(), c, c, |v, c, _| v < c));
+        }
+        for (start, end) in [(-1.0, 1.0), (f64::NEG_INFINITY, f64::INFINITY), (0.0, f64::INFINITY)] {
+            cases.push((pm.add("x".to_string(), predicates::between(d(start), d(end))).unwrap(), start, end, |v, s, e| v >= s && v <= e));
+        }
+
+        for v in [f64::NAN, -f64::NAN, f64::INFINITY, f64::NEG_INFINITY] {
+            let results = pm.evaluate(&Event{ values: vec![EventValue{ name: "x".to_string(), value: d(v) }] });
+            assert_eq!(cases.len(), results.len());
+            for (id, a, b, expected) in &cases {
+                let result = results.iter().find(|r| r.id == *id).unwrap().result;
+                let expected = if v.is_nan() { None } else { Some(expected(v, *a, *b)) };
+                assert_eq!(expected, result, "value {} against case {} {}", v, a, b);
+            }
 
-            tree.insert(root.clone());
+            let checked = Event::checked(vec![
+                EventValue{ name: "y".to_string(), value: d(1.0) },
+                EventValue{ name: "x".to_string(), value: d(v) },
+            ]);
+            assert_eq!(Some(EventError::NonFinite { attribute: "x".to_string() }), checked.err());
         }
+        assert!(Event::checked(vec![EventValue{ name: "x".to_string(), value: d(1e300) }]).is_ok());
+    }
 
+    #[test]
+    fn nan_hashes_canonically(){
+        use crate::predicates::Double;
 
+        let id = |v: f64| predicates::equal(Value::Double(Double::new(v))).id();
+        assert_eq!(id(f64::NAN), id(-f64::NAN));
+        assert_eq!(id(f64::NAN), id(f64::from_bits(f64::NAN.to_bits() | 1)));
+        assert_ne!(id(f64::NAN), id(f64::INFINITY));
+    }
 
-        assert_eq!(4, tree.get_m());
+    #[test]
+    fn audit_reports_both_wiring_gaps(){
+        let mut pm = PredicateStore::new();
+        let price = pm.add("price".to_string(), predicates::greater(Int(100))).unwrap();
+        let country = pm.add("country".to_string(), predicates::equal(Value::String("DE".to_string()))).unwrap();
+        let unused = pm.add("age".to_string(), predicates::less(Int(18))).unwrap();
+        let missing = 0x5EED_0000_0000_0001;
+
+        let expression = |id: &str, leaves: &[u64]| {
+            let mut root = NodeType::new_root(RootNode::and(id.to_string()));
+            for leaf in leaves {
+                add_children(&mut root, &mut NodeType::new_leaf(LeafNode::new(*leaf)));
+            }
+            root
+        };
+
+        let mut tree = ATree::new();
+        tree.insert_wired(expression("wired", &[price, country]), &pm).unwrap();
+        assert_eq!(
+            Err(ATreeError::MissingPredicate { predicate_id: missing }),
+            tree.insert_wired(expression("dangling", &[price, missing]), &pm).map(|_| ())
+        );
+        assert_eq!(AuditReport{ predicates_without_expressions: vec![unused], ..AuditReport::default() }, tree.audit_unreferenced(&pm));
+
+        tree.insert(expression("dangling", &[price, missing])).unwrap();
+        let report = tree.audit_unreferenced(&pm);
+        assert_eq!(vec![unused], report.predicates_without_expressions);
+        assert_eq!(vec!["dangling".to_string()], report.expressions_with_missing_predicates);
+
+        let event = Event{ values: vec![
+            EventValue{ name: "price".to_string(), value: Int(200) },
+            EventValue{ name: "age".to_string(), value: Int(30) },
+        ] };
+        let results = pm.evaluate(&event);
+        assert_eq!(2, results.len());
+        assert_eq!(1, tree.matches_with_outcome(&results).unreferenced_results);
+        let unknown_age = [PredResult{ id: unused, result: None }];
+        assert_eq!(1, tree.matches_with_outcome(&unknown_age).unreferenced_results);
+    }
+
+    #[test]
+    fn pattern_predicates_respect_context_shadowing(){
+        let mut pm = PredicateStore::new();
+        let id = pm.add_pattern("device.*", predicates::equal(Int(1))).unwrap();
+        pm.set_context(Event{ values: vec![EventValue{ name: "device.os".to_string(), value: Int(1) }] });
+
+        let event = Event{ values: vec![EventValue{ name: "device.os".to_string(), value: Int(2) }] };
+        assert_eq!(vec![PredResult{ id, result: Some(false) }], pm.evaluate(&event));
+        assert_eq!(vec![PredResult{ id, result: Some(true) }], pm.evaluate(&Event{ values: vec![] }));
     }
 
     #[test]
@@ -875,8 +3140,8 @@ mod tests{
         let mut tree = ATree::new();
 
         {
-            let eq_id = pm.add("A1".to_string(), predicates::equal(Int(10)));
-            let gt_id = pm.add("A1".to_string(), predicates::greater(Int(5)));
+            let eq_id = pm.add("A1".to_string(), predicates::equal(Int(10))).unwrap();
+            let gt_id = pm.add("A1".to_string(), predicates::greater(Int(5))).unwrap();
 
 
             let mut leaf = NodeType::new_leaf(LeafNode::new(eq_id));
@@ -888,7 +3153,7 @@ mod tests{
 
             expressions.insert(root.borrow().get_id());
 
-            tree.insert(root.clone());
+            tree.insert(root.clone()).unwrap();
         }
 
         let event = Event{
@@ -0,0 +1,95 @@
+//! Three-valued (Kleene) logic over `Option<bool>`, where `None` is unknown.
+
+pub fn kleene_and(lhs: Option<bool>, rhs: Option<bool>) -> Option<bool>{
+    match (lhs, rhs) {
+        (Some(false), _) | (_, Some(false)) => Some(false),
+        (Some(true), Some(true)) => Some(true),
+        _ => None
+    }
+}
+
+pub fn kleene_or(lhs: Option<bool>, rhs: Option<bool>) -> Option<bool>{
+    match (lhs, rhs) {
+        (Some(true), _) | (_, Some(true)) => Some(true),
+        (Some(false), Some(false)) => Some(false),
+        _ => None
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    const VALUES: [Option<bool>; 3] = [Some(true), Some(false), None];
+
+    #[test]
+    fn and_truth_table(){
+        let table = [
+            (Some(true), Some(true), Some(true)),
+            (Some(true), Some(false), Some(false)),
+            (Some(true), None, None),
+            (Some(false), Some(true), Some(false)),
+            (Some(false), Some(false), Some(false)),
+            (Some(false), None, Some(false)),
+            (None, Some(true), None),
+            (None, Some(false), Some(false)),
+            (None, None, None),
+        ];
+        for (lhs, rhs, expected) in table {
+            assert_eq!(expected, kleene_and(lhs, rhs), "{:?} and {:?}", lhs, rhs);
+        }
+    }
+
+    #[test]
+    fn or_truth_table(){
+        let table = [
+            (Some(true), Some(true), Some(true)),
+            (Some(true), Some(false), Some(true)),
+            (Some(true), None, Some(true)),
+            (Some(false), Some(true), Some(true)),
+            (Some(false), Some(false), Some(false)),
+            (Some(false), None, None),
+            (None, Some(true), Some(true)),
+            (None, Some(false), None),
+            (None, None, None),
+        ];
+        for (lhs, rhs, expected) in table {
+            assert_eq!(expected, kleene_or(lhs, rhs), "{:?} or {:?}", lhs, rhs);
+        }
+    }
+
+    #[test]
+    fn operations_are_commutative_and_associative(){
+        for op in [kleene_and, kleene_or] {
+            for a in VALUES {
+                for b in VALUES {
+                    assert_eq!(op(a, b), op(b, a));
+                    for c in VALUES {
+                        assert_eq!(op(op(a, b), c), op(a, op(b, c)));
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn operations_agree_with_bool_on_known_values(){
+        for a in [true, false] {
+            for b in [true, false] {
+                assert_eq!(Some(a && b), kleene_and(Some(a), Some(b)));
+                assert_eq!(Some(a || b), kleene_or(Some(a), Some(b)));
+            }
+        }
+    }
+
+    #[test]
+    fn de_morgan_holds(){
+        let not = |v: Option<bool>| v.map(|v| !v);
+        for a in VALUES {
+            for b in VALUES {
+                assert_eq!(not(kleene_and(a, b)), kleene_or(not(a), not(b)));
+                assert_eq!(not(kleene_or(a, b)), kleene_and(not(a), not(b)));
+            }
+        }
+    }
+}
@@ -0,0 +1,82 @@
+//! Attribute name patterns over dotted namespaces, e.g. `*.geo.country` or
+//! `device.*`. A `*` stands for exactly one whole segment.
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+
+#[derive(Debug, PartialEq)]
+enum Segment{
+    Any,
+    Exact(String)
+}
+
+#[derive(Debug, PartialEq)]
+pub struct AttributePattern{
+    pattern: String,
+    segments: Vec<Segment>
+}
+
+impl AttributePattern{
+    pub fn new(pattern: &str) -> Self{
+        Self{
+            pattern: pattern.to_string(),
+            segments: pattern.split('.').map(|segment| match segment {
+                "*" => Segment::Any,
+                s => Segment::Exact(s.to_string())
+            }).collect()
+        }
+    }
+
+    pub fn as_str(&self) -> &str{
+        &self.pattern
+    }
+
+    pub fn matches(&self, attribute: &str) -> bool{
+        let mut parts = attribute.split('.');
+        for segment in &self.segments {
+            match (segment, parts.next()) {
+                (_, None) => return false,
+                (Segment::Any, Some(_)) => {}
+                (Segment::Exact(expected), Some(part)) => {
+                    if expected != part {
+                        return false;
+                    }
+                }
+            }
+        }
+        parts.next().is_none()
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    fn wildcard_matches_one_whole_segment(){
+        let pattern = AttributePattern::new("*.geo.country");
+        assert!(pattern.matches("device.geo.country"));
+        assert!(pattern.matches("user.geo.country"));
+        assert!(!pattern.matches("geo.country"));
+        assert!(!pattern.matches("device.geoX.country"));
+        assert!(!pattern.matches("device.geo.countryX"));
+        assert!(!pattern.matches("a.device.geo.country"));
+    }
+
+    #[test]
+    fn trailing_wildcard(){
+        let pattern = AttributePattern::new("device.*");
+        assert!(pattern.matches("device.os"));
+        assert!(!pattern.matches("device"));
+        assert!(!pattern.matches("devices.os"));
+        assert!(!pattern.matches("device.geo.country"));
+    }
+
+    #[test]
+    fn pattern_without_wildcard_is_exact(){
+        let pattern = AttributePattern::new("device.os");
+        assert!(pattern.matches("device.os"));
+        assert!(!pattern.matches("device.osx"));
+        assert_eq!("device.os", pattern.as_str());
+    }
+}
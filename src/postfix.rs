@@ -0,0 +1,269 @@
+//! Builds expressions from postfix token streams, as some upstream systems
+//! ship them (`p1 p2 AND(2) p3 OR(2)`), and writes them back out.
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+use crate::predicates::{Predicate, PredicateKind};
+use crate::{add_children, ArcNodeLink, InnerNode, LeafNode, LogOperation, Node, NodeType, PredicateError, PredicateStore, RootNode};
+
+pub enum PostfixToken{
+    Pred(u64),
+    /// A predicate on an attribute, added to the store while the stream is
+    /// read and then used like `Pred` with the id the store hands out.
+    PredInline(PredicateKind, String),
+    And(usize),
+    Or(usize),
+    /// Negates the expression on top of the stack.
+    Not,
+    /// True when at least the first number of the last `arity` operands are.
+    AtLeast(u32, usize)
+}
+
+impl fmt::Debug for PostfixToken{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PostfixToken::Pred(id) => f.debug_tuple("Pred").field(id).finish(),
+            PostfixToken::PredInline(predicate, attribute) => f.debug_tuple("PredInline").field(&predicate.id()).field(attribute).finish(),
+            PostfixToken::And(arity) => f.debug_tuple("And").field(arity).finish(),
+            PostfixToken::Or(arity) => f.debug_tuple("Or").field(arity).finish(),
+            PostfixToken::Not => f.write_str("Not"),
+            PostfixToken::AtLeast(threshold, arity) => f.debug_tuple("AtLeast").field(threshold).field(arity).finish()
+        }
+    }
+}
+
+/// Inline predicates are equal if they are the same predicate on the same
+/// attribute, which is what their id stands for.
+impl PartialEq for PostfixToken{
+    fn eq(&self, other: &Self) -> bool {
+        match (self, other) {
+            (PostfixToken::Pred(a), PostfixToken::Pred(b)) => a == b,
+            (PostfixToken::PredInline(a, a_attribute), PostfixToken::PredInline(b, b_attribute)) => a.id() == b.id() && a_attribute == b_attribute,
+            (PostfixToken::And(a), PostfixToken::And(b)) | (PostfixToken::Or(a), PostfixToken::Or(b)) => a == b,
+            (PostfixToken::Not, PostfixToken::Not) => true,
+            (PostfixToken::AtLeast(a, a_arity), PostfixToken::AtLeast(b, b_arity)) => a == b && a_arity == b_arity,
+            _ => false
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum PostfixError{
+    Empty,
+    /// An operator at `position` has an arity of zero.
+    ZeroArity { position: usize },
+    /// An operator at `position` needs more operands than are on the stack.
+    StackUnderflow { position: usize, needed: usize, available: usize },
+    /// The stream ended with more than one expression on the stack.
+    LeftoverOperands(usize),
+    /// The store rejected the inline predicate at `position`.
+    Predicate { position: usize, error: PredicateError }
+}
+
+enum Expr{
+    Pred(u64),
+    Op(LogOperation, Vec<Expr>)
+}
+
+/// Builds the expression `id` from `tokens`. Operators take the given number
+/// of operands and become one n-ary node. The last operator becomes the
+/// root; a stream that is a single predicate becomes an OR root over it.
+/// Inline predicates are added to `store` as they are read.
+pub fn from_postfix(id: &str, tokens: Vec<PostfixToken>, store: &mut PredicateStore) -> Result<ArcNodeLink, PostfixError>{
+    let mut stack: Vec<Expr> = vec![];
+    for (position, token) in tokens.into_iter().enumerate() {
+        let (operation, arity) = match token {
+            PostfixToken::Pred(predicate_id) => {
+                stack.push(Expr::Pred(predicate_id));
+                continue;
+            }
+            PostfixToken::PredInline(predicate, attribute) => {
+                let predicate_id = store.add(attribute, predicate).map_err(|error| PostfixError::Predicate { position, error })?;
+                stack.push(Expr::Pred(predicate_id));
+                continue;
+            }
+            PostfixToken::And(arity) => (LogOperation::And, arity),
+            PostfixToken::Or(arity) => (LogOperation::Or, arity),
+            PostfixToken::Not => (LogOperation::Not, 1),
+            PostfixToken::AtLeast(threshold, arity) => (LogOperation::AtLeast(threshold), arity)
+        };
+        if arity == 0 {
+            return Err(PostfixError::ZeroArity { position });
+        }
+        if arity > stack.len() {
+            return Err(PostfixError::StackUnderflow { position, needed: arity, available: stack.len() });
+        }
+        let operands = stack.split_off(stack.len() - arity);
+        stack.push(Expr::Op(operation, operands));
+    }
+
+    let expr = match stack.len() {
+        0 => return Err(PostfixError::Empty),
+        1 => stack.pop().unwrap(),
+        n => return Err(PostfixError::LeftoverOperands(n - 1))
+    };
+    let (operation, operands) = match expr {
+        Expr::Op(operation, operands) => (operation, operands),
+        pred => (LogOperation::Or, vec![pred])
+    };
+    let mut root = NodeType::new_root(RootNode::new(String::from(id), operation));
+    for operand in operands {
+        add_children(&mut root, &mut build(operand));
+    }
+    Ok(root)
+}
+
+fn build(expr: Expr) -> ArcNodeLink{
+    match expr {
+        Expr::Pred(predicate_id) => NodeType::new_leaf(LeafNode::new(predicate_id)),
+        Expr::Op(operation, operands) => {
+            let mut inner = NodeType::new_inner(InnerNode::new(operation));
+            for operand in operands {
+                add_children(&mut inner, &mut build(operand));
+            }
+            inner
+        }
+    }
+}
+
+/// Writes the expression under `node` as postfix tokens, children first in
+/// their stored order. Leaves are written as `Pred`, inline predicates
+/// included.
+pub fn to_postfix(node: &ArcNodeLink) -> Vec<PostfixToken>{
+    let mut tokens = vec![];
+    write(node, &mut tokens);
+    tokens
+}
+
+fn write(node: &ArcNodeLink, tokens: &mut Vec<PostfixToken>){
+    let node = node.borrow();
+    let (operation, children) = match &*node {
+        NodeType::LeafNodeType(leaf) => {
+            tokens.push(PostfixToken::Pred(leaf.get_id()));
+            return;
+        }
+        NodeType::InnerNodeType(inner) => (&inner.log_operation, inner.get_children().unwrap_or_default()),
+        NodeType::RootNodeType(root) => (&root.log_operation, root.get_children().unwrap_or_default())
+    };
+    for child in children {
+        write(child, tokens);
+    }
+    tokens.push(match operation {
+        LogOperation::And => PostfixToken::And(children.len()),
+        LogOperation::Or => PostfixToken::Or(children.len()),
+        LogOperation::Not => PostfixToken::Not,
+        LogOperation::AtLeast(threshold) => PostfixToken::AtLeast(*threshold, children.len())
+    });
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::test_rng::XorShift;
+    use crate::ATree;
+
+    fn random_tokens(rng: &mut XorShift, depth: u32, tokens: &mut Vec<PostfixToken>){
+        if depth == 0 || rng.below(3) == 0 {
+            tokens.push(PostfixToken::Pred(rng.next() | 1 << 40));
+            return;
+        }
+        let arity = 2 + rng.below(3) as usize;
+        let operator = match rng.below(3) {
+            0 => PostfixToken::And(arity),
+            1 => PostfixToken::Or(arity),
+            _ => PostfixToken::AtLeast(1 + rng.below(arity as u64) as u32, arity)
+        };
+        let negated = rng.below(4) == 0;
+        for _ in 0..arity {
+            random_tokens(rng, depth - 1, tokens);
+        }
+        tokens.push(operator);
+        if negated {
+            tokens.push(PostfixToken::Not);
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn round_trips_random_expressions(){
+        let mut rng = XorShift(0x9E37_79B9_7F4A_7C15);
+        for _ in 0..200 {
+            let (mut replay, mut tokens, mut expected) = (rng.clone(), vec![], vec![]);
+            random_tokens(&mut rng, 4, &mut tokens);
+            if let [PostfixToken::Pred(_)] = tokens[..] {
+                continue;
+            }
+            random_tokens(&mut replay, 4, &mut expected);
+            assert_eq!(expected, to_postfix(&from_postfix("e", tokens, &mut PredicateStore::new()).unwrap()));
+        }
+    }
+
+    #[test]
+    fn operators_build_flat_n_ary_nodes(){
+        use PostfixToken::*;
+        let mut store = PredicateStore::new();
+
+        let root = from_postfix("e", vec![Pred(1 << 40), Pred(2 << 40), Pred(3 << 40), And(3), Pred(4 << 40), Or(2)], &mut store).unwrap();
+        assert_eq!(2, root.borrow().get_children().unwrap().len());
+        assert_eq!(3, root.borrow().get_children().unwrap()[0].borrow().get_children().unwrap().len());
+        assert_eq!(3, root.borrow().get_level());
+
+        let single = from_postfix("single", vec![Pred(7)], &mut store).unwrap();
+        assert_eq!(vec![Pred(7), Or(1)], to_postfix(&single));
+
+        let mut tree = ATree::new();
+        tree.insert(root).unwrap();
+        tree.insert(single).unwrap();
+        let results = [1u64, 2, 3].map(|n| crate::PredResult{ id: n << 40, result: Some(true) });
+        assert!(tree.matches(&results).contains("e"));
+        assert!(tree.matches(&[crate::PredResult{ id: 7, result: Some(true) }]).contains("single"));
+    }
+
+    #[test]
+    fn malformed_streams_are_rejected(){
+        use PostfixToken::*;
+        let mut store = PredicateStore::new();
+
+        assert_eq!(Some(PostfixError::Empty), from_postfix("e", vec![], &mut store).err());
+        assert_eq!(
+            Some(PostfixError::StackUnderflow { position: 1, needed: 2, available: 1 }),
+            from_postfix("e", vec![Pred(1), And(2)], &mut store).err()
+        );
+        assert_eq!(Some(PostfixError::LeftoverOperands(1)), from_postfix("e", vec![Pred(1), Pred(2), Pred(3), Or(2)], &mut store).err());
+        assert_eq!(Some(PostfixError::ZeroArity { position: 1 }), from_postfix("e", vec![Pred(1), Or(0)], &mut store).err());
+        assert_eq!(
+            Some(PostfixError::StackUnderflow { position: 0, needed: 1, available: 0 }),
+            from_postfix("e", vec![Not], &mut store).err()
+        );
+        assert_eq!(Some(PostfixError::ZeroArity { position: 2 }), from_postfix("e", vec![Pred(1), Pred(2), AtLeast(1, 0)], &mut store).err());
+    }
+
+    #[test]
+    fn inline_predicates_are_added_to_the_store(){
+        use crate::predicates::{equal, greater, Value};
+        use crate::{Event, EventValue};
+        use PostfixToken::*;
+
+        let mut store = PredicateStore::new();
+        let registered = store.add(String::from("price"), greater(Value::Int(10))).unwrap();
+        let tokens = vec![
+            Pred(registered),
+            PredInline(equal(Value::String(String::from("DE"))).into_kind(), String::from("country")),
+            And(2)
+        ];
+        let root = from_postfix("e", tokens, &mut store).unwrap();
+        let inline = store.add(String::from("country"), equal(Value::String(String::from("DE")))).unwrap();
+        assert_eq!(vec![Pred(registered), Pred(inline), And(2)], to_postfix(&root));
+
+        let mut tree = ATree::new();
+        tree.insert(root).unwrap();
+        let event = Event::new(vec![
+            EventValue{ name: String::from("price"), value: Value::Int(20) },
+            EventValue{ name: String::from("country"), value: Value::String(String::from("DE")) }
+        ]);
+        assert!(tree.matches(&store.evaluate(&event)).contains("e"));
+    }
+}
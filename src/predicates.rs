@@ -1,44 +1,200 @@
 
+mod bloom;
+mod collation;
 pub mod logical_operations;
 
+pub use crate::predicates::collation::Collation;
+
 use crate::predicates::EqOperation::{Equal, NotEqual};
 use crate::predicates::OrdOperation::{Greater, GreaterEqual, Less, LessEqual};
 use crate::predicates::SetOperation::{ElementOf, NotElementOf};
-use std::cmp::Ordering;
-use std::hash::{DefaultHasher, Hash, Hasher};
-
+use crate::hash::{self, IdHasher};
+use crate::predicates::bloom::BloomFilter;
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::cmp::Ordering;
+use core::hash::{Hash, Hasher};
+
+/// A double that compares approximately. NaN is equal to nothing and
+/// unordered; infinities equal themselves and order as usual.
 #[derive(Debug, Copy, Clone)]
 pub struct Double(f64);
+
+impl Double{
+    pub fn new(value: f64) -> Self{
+        Double(value)
+    }
+
+    pub fn value(&self) -> f64{
+        self.0
+    }
+}
+
 impl Hash for Double{
     fn hash<H: Hasher>(&self, state: &mut H) {
-        self.0.to_string().hash(state)
+        // Every NaN payload hashes the same.
+        if self.0.is_nan() {
+            "NaN".hash(state)
+        } else {
+            self.0.to_string().hash(state)
+        }
     }
 }
 impl PartialEq for Double{
     fn eq(&self, other: &Self) -> bool {
-        (self.0 - other.0).abs() < 0.0001
+        self.0 == other.0 || (self.0 - other.0).abs() < 0.0001
     }
 }
 
 impl PartialOrd for Double{
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        if !self.0.is_finite() || !other.0.is_finite() {
+            return self.0.partial_cmp(&other.0);
+        }
         let int_part_self = self.0 as i32;
         let int_part_other = other.0 as i32;
         int_part_self.partial_cmp(&int_part_other)
     }
 }
 
-#[derive(Hash, PartialEq, PartialOrd, Debug)]
+#[derive(Hash, PartialEq, PartialOrd, Debug, Clone)]
 pub enum Value{
     Int(i32),
     Double(Double),
     String(String),
-    Bool(bool)
+    Bool(bool),
+    Binary(Vec<u8>)
+}
+
+impl Value{
+    /// Parses a hex string such as `"deadBEEF"` into a `Binary` value. Returns
+    /// `None` for odd lengths or non-hex characters.
+    pub fn from_hex(hex: &str) -> Option<Value>{
+        if !hex.len().is_multiple_of(2) {
+            return None;
+        }
+        let digit = |c: u8| (c as char).to_digit(16);
+        hex.as_bytes().chunks(2)
+            .map(|pair| Some((digit(pair[0])? * 16 + digit(pair[1])?) as u8))
+            .collect::<Option<Vec<u8>>>()
+            .map(Value::Binary)
+    }
+
+    pub fn is_nan(&self) -> bool{
+        matches!(self, Value::Double(d) if d.0.is_nan())
+    }
+
+    pub fn is_finite(&self) -> bool{
+        !matches!(self, Value::Double(d) if !d.0.is_finite())
+    }
 }
 
 pub trait Predicate {
     fn id(&self) -> u64;
     fn evaluate(&self, value: &Value) -> bool;
+
+    /// Appends one result per entry of `values` to `out`, unknown for NaN
+    /// like `PredicateStore` reports it. Implementations override this to set
+    /// up their comparison once instead of per value.
+    fn evaluate_batch(&self, values: &[Value], out: &mut Vec<Option<bool>>){
+        extend_known(values, out, |value| self.evaluate(value));
+    }
+
+    /// How the predicate store keeps this predicate. Built-in predicates
+    /// return their own variant so they are evaluated without dynamic
+    /// dispatch; everything else is boxed.
+    fn into_kind(self) -> PredicateKind where Self: Sized + 'static{
+        PredicateKind::Custom(Box::new(self))
+    }
+}
+
+/// Appends `evaluate` of each value to `out`. NaN has no answer for any
+/// predicate, so it is unknown.
+fn extend_known(values: &[Value], out: &mut Vec<Option<bool>>, evaluate: impl Fn(&Value) -> bool){
+    out.extend(values.iter().map(|value| if value.is_nan() { None } else { Some(evaluate(value)) }));
+}
+
+pub enum PredicateKind{
+    Equal(EqualPredicate),
+    Ord(OrdPredicate),
+    Set(SetPredicate),
+    Between(BetweenPredicate),
+    BytePrefix(BytePrefixPredicate),
+    Custom(Box<dyn Predicate>)
+}
+
+/// The kind of a built-in predicate, kept in the top byte of its id so a raw
+/// id can be traced back to what produced it. Ids of custom predicates carry
+/// whatever top byte they were given.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PredicateKindTag{
+    Equal = 0x01,
+    Ord = 0x02,
+    Set = 0x03,
+    Between = 0x04,
+    BytePrefix = 0x05,
+    Cross = 0x06
+}
+
+impl PredicateKindTag{
+    fn tag(self, hash: u64) -> u64{
+        hash::tagged(self as u8, hash)
+    }
+}
+
+/// The kind a predicate id was tagged with, or `None` if its top byte isn't
+/// a kind tag. Ids handed out by `PredicateStore` keep the tag of the
+/// predicate they were registered for.
+pub fn predicate_kind_of(id: u64) -> Option<PredicateKindTag>{
+    Some(match hash::tag_of(id) {
+        0x01 => PredicateKindTag::Equal,
+        0x02 => PredicateKindTag::Ord,
+        0x03 => PredicateKindTag::Set,
+        0x04 => PredicateKindTag::Between,
+        0x05 => PredicateKindTag::BytePrefix,
+        0x06 => PredicateKindTag::Cross,
+        _ => return None
+    })
+}
+
+impl Predicate for PredicateKind{
+    fn id(&self) -> u64 {
+        match self {
+            PredicateKind::Equal(p) => p.id(),
+            PredicateKind::Ord(p) => p.id(),
+            PredicateKind::Set(p) => p.id(),
+            PredicateKind::Between(p) => p.id(),
+            PredicateKind::BytePrefix(p) => p.id(),
+            PredicateKind::Custom(p) => p.id()
+        }
+    }
+
+    fn evaluate(&self, value: &Value) -> bool {
+        match self {
+            PredicateKind::Equal(p) => p.evaluate(value),
+            PredicateKind::Ord(p) => p.evaluate(value),
+            PredicateKind::Set(p) => p.evaluate(value),
+            PredicateKind::Between(p) => p.evaluate(value),
+            PredicateKind::BytePrefix(p) => p.evaluate(value),
+            PredicateKind::Custom(p) => p.evaluate(value)
+        }
+    }
+
+    fn evaluate_batch(&self, values: &[Value], out: &mut Vec<Option<bool>>) {
+        match self {
+            PredicateKind::Equal(p) => p.evaluate_batch(values, out),
+            PredicateKind::Ord(p) => p.evaluate_batch(values, out),
+            PredicateKind::Set(p) => p.evaluate_batch(values, out),
+            PredicateKind::Between(p) => p.evaluate_batch(values, out),
+            PredicateKind::BytePrefix(p) => p.evaluate_batch(values, out),
+            PredicateKind::Custom(p) => p.evaluate_batch(values, out)
+        }
+    }
+
+    fn into_kind(self) -> PredicateKind {
+        self
+    }
 }
 
 
@@ -63,10 +219,10 @@ impl EqualPredicate {
 
 impl  Predicate for EqualPredicate {
     fn id(&self) -> u64 {
-        let mut h = DefaultHasher::new();
+        let mut h = IdHasher::new();
         self.constant.hash(&mut h);
         self.operation.hash(&mut h);
-        h.finish()
+        PredicateKindTag::Equal.tag(h.finish())
     }
 
     fn evaluate(&self, value: &Value) -> bool
@@ -76,6 +232,31 @@ impl  Predicate for EqualPredicate {
             EqOperation::NotEqual => {value.ne(&self.constant)}
         }
     }
+
+    /// Picks the comparison for the constant's type once; values of another
+    /// type are never equal to it.
+    fn evaluate_batch(&self, values: &[Value], out: &mut Vec<Option<bool>>) {
+        let negate = matches!(self.operation, NotEqual);
+        match &self.constant {
+            Value::Int(constant) => {
+                let constant = *constant;
+                extend_known(values, out, |value| matches!(value, Value::Int(v) if *v == constant) != negate);
+            }
+            Value::Double(constant) => {
+                let constant = *constant;
+                extend_known(values, out, |value| matches!(value, Value::Double(v) if *v == constant) != negate);
+            }
+            Value::String(constant) => {
+                let constant = constant.as_str();
+                extend_known(values, out, |value| matches!(value, Value::String(v) if v == constant) != negate);
+            }
+            constant => extend_known(values, out, |value| (value == constant) != negate)
+        }
+    }
+
+    fn into_kind(self) -> PredicateKind {
+        PredicateKind::Equal(self)
+    }
 }
 
 pub fn equal(value: Value) -> EqualPredicate{
@@ -87,7 +268,7 @@ pub fn not_equal(value: Value) -> EqualPredicate{
 }
 
 
-#[derive(Hash)]
+#[derive(Hash, Clone)]
 pub enum OrdOperation{
     Greater,GreaterEqual,LessEqual,Less
 }
@@ -95,33 +276,91 @@ pub enum OrdOperation{
 pub struct OrdPredicate {
     constant: Value,
     operation: OrdOperation,
+    collation: Collation
 }
 
 impl OrdPredicate{
     pub fn new(constant: Value, operation: OrdOperation) -> Self{
         Self{
             constant,
-            operation
+            operation,
+            collation: Collation::Binary
         }
     }
+
+    pub fn with_collation(mut self, collation: Collation) -> Self{
+        self.collation = collation;
+        self
+    }
 }
 
 impl Predicate for OrdPredicate {
     fn id(&self) -> u64 {
-        let mut h = DefaultHasher::new();
+        let mut h = IdHasher::new();
         self.constant.hash(&mut h);
         self.operation.hash(&mut h);
-        h.finish()
+        hash_collation(self.collation, &mut h);
+        PredicateKindTag::Ord.tag(h.finish())
     }
 
     fn evaluate(&self, value: &Value) -> bool {
+        let ordering = self.collation.compare(value, &self.constant);
         match self.operation {
-            OrdOperation::Greater => {value.gt(&self.constant)}
-            OrdOperation::GreaterEqual => {value.ge(&self.constant)}
-            OrdOperation::LessEqual => {value.le(&self.constant)}
-            OrdOperation::Less => {value.lt(&self.constant)}
+            OrdOperation::Greater => ordering == Some(Ordering::Greater),
+            OrdOperation::GreaterEqual => matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+            OrdOperation::LessEqual => matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+            OrdOperation::Less => ordering == Some(Ordering::Less)
+        }
+    }
+
+    /// Picks the comparison for the constant's type once. Values of another
+    /// type, and strings under a non-binary collation, go through the
+    /// collation like `evaluate` does.
+    fn evaluate_batch(&self, values: &[Value], out: &mut Vec<Option<bool>>) {
+        let accepts: fn(Option<Ordering>) -> bool = match self.operation {
+            Greater => |ordering| ordering == Some(Ordering::Greater),
+            GreaterEqual => |ordering| matches!(ordering, Some(Ordering::Greater | Ordering::Equal)),
+            LessEqual => |ordering| matches!(ordering, Some(Ordering::Less | Ordering::Equal)),
+            Less => |ordering| ordering == Some(Ordering::Less)
+        };
+        let fallback = |value: &Value| self.collation.compare(value, &self.constant);
+        match &self.constant {
+            Value::Int(constant) => {
+                let constant = *constant;
+                extend_known(values, out, |value| accepts(match value {
+                    Value::Int(v) => Some(v.cmp(&constant)),
+                    _ => fallback(value)
+                }));
+            }
+            Value::Double(constant) => {
+                let constant = *constant;
+                extend_known(values, out, |value| accepts(match value {
+                    Value::Double(v) => v.partial_cmp(&constant),
+                    _ => fallback(value)
+                }));
+            }
+            Value::String(constant) if self.collation == Collation::Binary => {
+                let constant = constant.as_str();
+                extend_known(values, out, |value| accepts(match value {
+                    Value::String(v) => Some(v.as_str().cmp(constant)),
+                    _ => fallback(value)
+                }));
+            }
+            _ => extend_known(values, out, |value| accepts(fallback(value)))
         }
     }
+
+    fn into_kind(self) -> PredicateKind {
+        PredicateKind::Ord(self)
+    }
+}
+
+/// Binary collation is left out of the id so ids of existing predicates don't
+/// change.
+fn hash_collation(collation: Collation, h: &mut IdHasher){
+    if collation != Collation::Binary {
+        collation.hash(h);
+    }
 }
 
 pub fn greater(value: Value) -> OrdPredicate{
@@ -140,43 +379,115 @@ pub fn less(value: Value) -> OrdPredicate{
     OrdPredicate::new(value, Less)
 }
 
+#[derive(Hash)]
 pub enum SetOperation{
     ElementOf, NotElementOf
 }
 
 pub struct SetPredicate{
     constants: Vec<Value>,
-    operation: SetOperation
+    operation: SetOperation,
+    bloom: Option<BloomFilter>,
+    /// The constants, sorted, while they are all integers.
+    sorted_ints: Option<Vec<i32>>
 }
 
 impl SetPredicate{
     pub fn new(constants: Vec<Value>, operation: SetOperation) -> Self{
+        let sorted_ints = constants.iter()
+            .map(|constant| match constant { Value::Int(i) => Some(*i), _ => None })
+            .collect::<Option<Vec<i32>>>()
+            .map(|mut ints| {
+                ints.sort_unstable();
+                ints
+            });
         Self{
             constants,
-            operation
+            operation,
+            bloom: None,
+            sorted_ints
         }
     }
 
+    /// Puts a bloom filter of `bits_per_key` bits per constant in front of
+    /// the set. Values it rules out skip the scan over the constants; the
+    /// results are the same with or without it.
+    pub fn with_bloom(mut self, bits_per_key: usize) -> Self{
+        let mut bloom = BloomFilter::new(self.constants.len(), bits_per_key);
+        for constant in &self.constants {
+            bloom.insert(Self::hash_value(constant));
+        }
+        self.bloom = Some(bloom);
+        self
+    }
+
     pub fn push(&mut self, value: Value){
+        if let Some(bloom) = &mut self.bloom {
+            bloom.insert(Self::hash_value(&value));
+        }
+        match (&mut self.sorted_ints, &value) {
+            (Some(ints), Value::Int(i)) => {
+                let position = ints.partition_point(|existing| existing < i);
+                ints.insert(position, *i);
+            }
+            (sorted_ints, _) => *sorted_ints = None
+        }
         self.constants.push(value)
     }
+
+    fn hash_value(value: &Value) -> u64{
+        let mut h = IdHasher::new();
+        value.hash(&mut h);
+        h.finish()
+    }
+
+    fn contains(&self, value: &Value) -> bool{
+        // Doubles compare approximately, so equal doubles can hash differently
+        // and must always take the exact path.
+        if let (Some(bloom), false) = (&self.bloom, matches!(value, Value::Double(_))) {
+            if !bloom.might_contain(Self::hash_value(value)) {
+                return false;
+            }
+        }
+        self.constants.contains(value)
+    }
 }
 
 impl Predicate for SetPredicate{
     fn id(&self) -> u64 {
-        let mut h = DefaultHasher::new();
+        let mut h = IdHasher::new();
+        self.operation.hash(&mut h);
         for constant in &self.constants {
             constant.hash(&mut h)
         }
-        h.finish()
+        PredicateKindTag::Set.tag(h.finish())
     }
 
     fn evaluate(&self, value: &Value) -> bool {
         match self.operation {
-            SetOperation::ElementOf => {self.constants.contains(&value)}
-            SetOperation::NotElementOf => {!self.constants.contains(&value)}
+            SetOperation::ElementOf => {self.contains(value)}
+            SetOperation::NotElementOf => {!self.contains(value)}
         }
     }
+
+    /// Sets of integers are kept sorted and searched per value; other sets
+    /// fall back to the linear scan, since `Double` equality is approximate.
+    fn evaluate_batch(&self, values: &[Value], out: &mut Vec<Option<bool>>) {
+        let negate = matches!(self.operation, NotElementOf);
+        match &self.sorted_ints {
+            Some(ints) => {
+                extend_known(values, out, |value| {
+                    let found = match value { Value::Int(i) => ints.binary_search(i).is_ok(), _ => false };
+                    found != negate
+                });
+            }
+            None => extend_known(values, out, |value| self.contains(value) != negate)
+        }
+    }
+
+    fn into_kind(self) -> PredicateKind {
+        PredicateKind::Set(self)
+    }
 }
 
 pub fn element_of(values: Vec<Value>) -> SetPredicate{
@@ -187,30 +498,96 @@ pub fn not_element_of(values: Vec<Value>) -> SetPredicate{
     SetPredicate::new(values, NotElementOf)
 }
 
+#[derive(Hash)]
+pub enum CrossOperation{
+    Eq(EqOperation),
+    Ord(OrdOperation)
+}
+
+/// Compares two attributes of the same event, e.g. `bid_floor <= max_cpm`.
+/// Values of different types never order; equality follows `Value`'s.
+pub struct CrossAttrPredicate{
+    left_attr: String,
+    right_attr: String,
+    operation: CrossOperation
+}
+
+impl CrossAttrPredicate{
+    pub fn new(left_attr: &str, right_attr: &str, operation: CrossOperation) -> Self{
+        Self{
+            left_attr: left_attr.to_string(),
+            right_attr: right_attr.to_string(),
+            operation
+        }
+    }
+
+    pub fn left_attr(&self) -> &str{
+        &self.left_attr
+    }
+
+    pub fn right_attr(&self) -> &str{
+        &self.right_attr
+    }
+
+    pub fn id(&self) -> u64{
+        let mut h = IdHasher::new();
+        self.left_attr.hash(&mut h);
+        self.right_attr.hash(&mut h);
+        self.operation.hash(&mut h);
+        PredicateKindTag::Cross.tag(h.finish())
+    }
+
+    pub fn evaluate(&self, left: &Value, right: &Value) -> bool{
+        match &self.operation {
+            _ if left.is_nan() || right.is_nan() => false,
+            CrossOperation::Eq(Equal) => left.eq(right),
+            CrossOperation::Eq(NotEqual) => left.ne(right),
+            CrossOperation::Ord(_) if core::mem::discriminant(left) != core::mem::discriminant(right) => false,
+            CrossOperation::Ord(Greater) => left.gt(right),
+            CrossOperation::Ord(GreaterEqual) => left.ge(right),
+            CrossOperation::Ord(LessEqual) => left.le(right),
+            CrossOperation::Ord(Less) => left.lt(right)
+        }
+    }
+}
+
 pub struct BetweenPredicate {
     start_constant: Value,
     end_constant: Value,
+    collation: Collation
 }
 
 impl BetweenPredicate{
     fn new(start_constant: Value, end_constant: Value) -> Self{
         Self{
             start_constant,
-            end_constant
+            end_constant,
+            collation: Collation::Binary
         }
     }
+
+    pub fn with_collation(mut self, collation: Collation) -> Self{
+        self.collation = collation;
+        self
+    }
 }
 
 impl Predicate for BetweenPredicate{
     fn id(&self) -> u64 {
-        let mut h = DefaultHasher::new();
+        let mut h = IdHasher::new();
         self.start_constant.hash(&mut h);
         self.end_constant.hash(&mut h);
-        h.finish()
+        hash_collation(self.collation, &mut h);
+        PredicateKindTag::Between.tag(h.finish())
     }
 
     fn evaluate(&self, value: &Value) -> bool {
-        value.ge(&self.start_constant) && value.le(&self.end_constant)
+        matches!(self.collation.compare(value, &self.start_constant), Some(Ordering::Greater | Ordering::Equal))
+            && matches!(self.collation.compare(value, &self.end_constant), Some(Ordering::Less | Ordering::Equal))
+    }
+
+    fn into_kind(self) -> PredicateKind {
+        PredicateKind::Between(self)
     }
 }
 
@@ -218,6 +595,56 @@ pub fn between(start: Value, end: Value) -> BetweenPredicate{
     BetweenPredicate::new(start, end)
 }
 
+#[derive(Hash)]
+pub enum PrefixOperation{
+    HasPrefix, NotHasPrefix
+}
+
+/// Matches `Binary` values by their leading bytes. Values of other types
+/// never have the prefix.
+pub struct BytePrefixPredicate{
+    prefix: Vec<u8>,
+    operation: PrefixOperation
+}
+
+impl BytePrefixPredicate{
+    pub fn new(prefix: Vec<u8>, operation: PrefixOperation) -> Self{
+        Self{
+            prefix,
+            operation
+        }
+    }
+}
+
+impl Predicate for BytePrefixPredicate{
+    fn id(&self) -> u64 {
+        let mut h = IdHasher::new();
+        self.prefix.hash(&mut h);
+        self.operation.hash(&mut h);
+        PredicateKindTag::BytePrefix.tag(h.finish())
+    }
+
+    fn evaluate(&self, value: &Value) -> bool {
+        let has_prefix = matches!(value, Value::Binary(bytes) if bytes.starts_with(&self.prefix));
+        match self.operation {
+            PrefixOperation::HasPrefix => has_prefix,
+            PrefixOperation::NotHasPrefix => !has_prefix
+        }
+    }
+
+    fn into_kind(self) -> PredicateKind {
+        PredicateKind::BytePrefix(self)
+    }
+}
+
+pub fn has_prefix(prefix: Vec<u8>) -> BytePrefixPredicate{
+    BytePrefixPredicate::new(prefix, PrefixOperation::HasPrefix)
+}
+
+pub fn not_has_prefix(prefix: Vec<u8>) -> BytePrefixPredicate{
+    BytePrefixPredicate::new(prefix, PrefixOperation::NotHasPrefix)
+}
+
 
 
 
@@ -228,6 +655,7 @@ mod tests{
     use super::*;
     use crate::predicates::Value::Bool;
     use crate::predicates::Value::Int;
+    use alloc::vec;
 
     #[test]
     fn not_equal_evaluation_for_same_value_is_false(){
@@ -270,6 +698,220 @@ mod tests{
 
     }
 
+    fn batch_values() -> Vec<Value>{
+        let mut values: Vec<Value> = (-3..13).map(Int).collect();
+        values.extend([9.5, 10.0, 10.00001, 10.7, -0.5, f64::NAN, -f64::NAN, f64::INFINITY].map(|d| Value::Double(Double(d))));
+        values.extend(["", "10", "B", "b", "c"].map(|s| Value::String(String::from(s))));
+        values.extend([Bool(true), Bool(false)]);
+        values
+    }
+
+    #[test]
+    fn evaluate_batch_equals_evaluate(){
+        let constants = || vec![Int(10), Value::Double(Double(10.0)), Value::String(String::from("b")), Bool(true)];
+        let mut predicates: Vec<Box<dyn Predicate>> = vec![];
+        for constant in constants() {
+            predicates.push(Box::new(equal(constant)));
+        }
+        for constant in constants() {
+            predicates.push(Box::new(not_equal(constant)));
+        }
+        for operation in [Greater, GreaterEqual, LessEqual, Less] {
+            for constant in constants() {
+                predicates.push(Box::new(OrdPredicate::new(constant, operation.clone())));
+            }
+        }
+        predicates.push(Box::new(element_of(vec![Int(10), Int(-2), Int(4)])));
+        predicates.push(Box::new(not_element_of(vec![Int(10), Int(-2), Int(4)])));
+        predicates.push(Box::new(element_of(constants())));
+        predicates.push(Box::new(not_element_of(constants())));
+        predicates.push(Box::new(element_of(vec![])));
+        predicates.push(Box::new(between(Int(0), Int(10))));
+        predicates.push(Box::new(greater(Value::String(String::from("B"))).with_collation(Collation::CaseInsensitiveAscii)));
+        let mut pushed = element_of(vec![Int(4)]);
+        pushed.push(Int(-2));
+        pushed.push(Int(11));
+        assert_eq!(Some(vec![-2, 4, 11]), pushed.sorted_ints);
+        predicates.push(Box::new(pushed));
+        let mut mixed = not_element_of(vec![Int(4)]);
+        mixed.push(Value::String(String::from("b")));
+        assert_eq!(None, mixed.sorted_ints);
+        predicates.push(Box::new(mixed));
+
+        let values = batch_values();
+        for predicate in &predicates {
+            let mut out = vec![Some(false)];
+            predicate.evaluate_batch(&values, &mut out);
+            let expected: Vec<Option<bool>> = values.iter().map(|value| (!value.is_nan()).then(|| predicate.evaluate(value))).collect();
+            assert_eq!(Some(false), out[0]);
+            assert_eq!(expected, out[1..]);
+        }
+    }
+
+    struct AlwaysTrue;
+
+    impl Predicate for AlwaysTrue{
+        fn id(&self) -> u64 {
+            7
+        }
+
+        fn evaluate(&self, _: &Value) -> bool {
+            true
+        }
+    }
+
+    #[test]
+    fn built_in_predicates_convert_to_their_own_kind(){
+        assert!(matches!(equal(Int(1)).into_kind(), PredicateKind::Equal(_)));
+        assert!(matches!(greater(Int(1)).into_kind(), PredicateKind::Ord(_)));
+        assert!(matches!(element_of(vec![Int(1)]).into_kind(), PredicateKind::Set(_)));
+        assert!(matches!(between(Int(1), Int(2)).into_kind(), PredicateKind::Between(_)));
+        assert!(matches!(equal(Int(1)).into_kind().into_kind(), PredicateKind::Equal(_)));
+
+        let custom = AlwaysTrue.into_kind();
+        assert!(matches!(custom, PredicateKind::Custom(_)));
+        assert_eq!(7, custom.id());
+        assert!(custom.evaluate(&Bool(false)));
+
+        let kind = greater_equal(Int(3)).into_kind();
+        assert_eq!(greater_equal(Int(3)).id(), kind.id());
+        assert!(kind.evaluate(&Int(3)) && !kind.evaluate(&Int(2)));
+    }
+
+    #[test]
+    fn predicates_of_different_kinds_get_different_ids(){
+        let ids = [
+            equal(Int(5)).id(), not_equal(Int(5)).id(),
+            greater(Int(5)).id(), greater_equal(Int(5)).id(), less_equal(Int(5)).id(), less(Int(5)).id(),
+            element_of(vec![Int(5)]).id(), not_element_of(vec![Int(5)]).id(),
+            between(Int(5), Int(5)).id(),
+        ];
+        for (i, id) in ids.iter().enumerate() {
+            assert!(!ids[..i].contains(id), "predicate {} reuses an id", i);
+        }
+
+        assert_eq!(Some(PredicateKindTag::Equal), predicate_kind_of(not_equal(Int(5)).id()));
+        assert_eq!(Some(PredicateKindTag::Ord), predicate_kind_of(less(Int(5)).id()));
+        assert_eq!(Some(PredicateKindTag::Set), predicate_kind_of(element_of(vec![Int(5)]).id()));
+        assert_eq!(Some(PredicateKindTag::Between), predicate_kind_of(between(Int(5), Int(5)).id()));
+        assert_eq!(Some(PredicateKindTag::BytePrefix), predicate_kind_of(has_prefix(vec![5]).id()));
+        assert_eq!(None, predicate_kind_of(AlwaysTrue.id()));
+    }
+
+    #[test]
+    fn kind_tag_separates_identical_payloads(){
+        // Both hash the constant and then an operation with discriminant 0,
+        // so only the tag tells them apart.
+        let (eq, gt) = (equal(Int(5)).id(), greater(Int(5)).id());
+        assert_eq!(eq << 8, gt << 8);
+        assert_ne!(eq, gt);
+        assert_eq!(0x01, eq >> 56);
+        assert_eq!(0x02, gt >> 56);
+    }
+
+    #[test]
+    fn collations_apply_to_ordering_predicates(){
+        let s = |value: &str| Value::String(String::from(value));
+
+        assert!(less(s("apple")).evaluate(&s("Zebra")));
+        assert!(!less(s("apple")).with_collation(Collation::CaseInsensitiveAscii).evaluate(&s("Zebra")));
+        assert!(greater_equal(s("APPLE")).with_collation(Collation::CaseInsensitiveAscii).evaluate(&s("apple")));
+        assert!(greater(s("file2")).with_collation(Collation::NumericAware).evaluate(&s("file10")));
+        assert!(!greater(s("file2")).evaluate(&s("file10")));
+
+        let names = between(s("a"), s("m")).with_collation(Collation::CaseInsensitiveAscii);
+        assert!(names.evaluate(&s("Bob")) && names.evaluate(&s("alice")) && names.evaluate(&s("M")));
+        assert!(!names.evaluate(&s("Nina")) && !between(s("a"), s("m")).evaluate(&s("Bob")));
+
+        let files = between(s("file2"), s("file10")).with_collation(Collation::NumericAware);
+        assert!(files.evaluate(&s("file9")) && !files.evaluate(&s("file11")) && !files.evaluate(&s("file1")));
+        assert!(between(Int(2), Int(10)).with_collation(Collation::NumericAware).evaluate(&Int(9)));
+
+        for collation in [Collation::CaseInsensitiveAscii, Collation::NumericAware] {
+            let predicate = less_equal(s("file10")).with_collation(collation);
+            let values = vec![s("FILE9"), s("file9"), s("file11"), Int(3)];
+            let mut out = vec![];
+            predicate.evaluate_batch(&values, &mut out);
+            let expected: Vec<Option<bool>> = values.iter().map(|value| (!value.is_nan()).then(|| predicate.evaluate(value))).collect();
+            assert_eq!(expected, out);
+        }
+
+        assert_eq!(less(s("a")).id(), less(s("a")).with_collation(Collation::Binary).id());
+        assert_ne!(less(s("a")).id(), less(s("a")).with_collation(Collation::NumericAware).id());
+        assert_ne!(
+            less(s("a")).with_collation(Collation::CaseInsensitiveAscii).id(),
+            less(s("a")).with_collation(Collation::NumericAware).id()
+        );
+        assert_ne!(between(s("a"), s("b")).id(), between(s("a"), s("b")).with_collation(Collation::CaseInsensitiveAscii).id());
+    }
+
+    #[test]
+    fn binary_values_parse_from_hex(){
+        assert_eq!(Some(Value::Binary(vec![0xde, 0xad, 0xbe, 0xef])), Value::from_hex("deadBEEF"));
+        assert_eq!(Some(Value::Binary(vec![])), Value::from_hex(""));
+        assert_eq!(None, Value::from_hex("abc"));
+        assert_eq!(None, Value::from_hex("zz"));
+        assert_eq!(None, Value::from_hex("é1"));
+    }
+
+    #[test]
+    fn byte_prefix_predicates(){
+        let value = Value::from_hex("deadbeef").unwrap();
+        assert!(has_prefix(vec![]).evaluate(&value));
+        assert!(has_prefix(vec![0xde, 0xad]).evaluate(&value));
+        assert!(has_prefix(vec![0xde, 0xad, 0xbe, 0xef]).evaluate(&value));
+        assert!(!has_prefix(vec![0xde, 0xad, 0xbe, 0xef, 0x00]).evaluate(&value));
+        assert!(!has_prefix(vec![0xad]).evaluate(&value));
+        assert!(!has_prefix(vec![]).evaluate(&Value::String(String::from("deadbeef"))));
+
+        assert!(!not_has_prefix(vec![0xde]).evaluate(&value));
+        assert!(not_has_prefix(vec![0xde, 0xad, 0xbe, 0xef, 0x00]).evaluate(&value));
+        assert!(not_has_prefix(vec![0xde]).evaluate(&Int(1)));
+        assert_ne!(has_prefix(vec![1]).id(), not_has_prefix(vec![1]).id());
+        assert!(matches!(has_prefix(vec![1]).into_kind(), PredicateKind::BytePrefix(_)));
+    }
+
+    #[test]
+    fn binary_equality_and_set_membership(){
+        let id = || Value::from_hex("0102ff").unwrap();
+        assert!(equal(id()).evaluate(&Value::Binary(vec![1, 2, 255])));
+        assert!(!equal(id()).evaluate(&Value::Binary(vec![1, 2])));
+        assert!(not_equal(id()).evaluate(&Value::String(String::from("0102ff"))));
+
+        let constants = || (0..100u8).map(|i| Value::Binary(vec![i, i])).collect::<Vec<_>>();
+        for set in [element_of(constants()), element_of(constants()).with_bloom(10)] {
+            assert!(set.evaluate(&Value::Binary(vec![7, 7])));
+            assert!(!set.evaluate(&Value::Binary(vec![7])));
+            assert!(!set.evaluate(&Value::Binary(vec![7, 7, 7])));
+            assert!(!set.evaluate(&Value::Binary(vec![200, 200])));
+        }
+        assert!(not_element_of(constants()).evaluate(&Value::Binary(vec![])));
+    }
+
+    #[test]
+    fn bloom_front_end_does_not_change_set_results(){
+        let constants = || {
+            let mut constants: Vec<Value> = (0..2_000).map(|i| Value::String(i.to_string())).collect();
+            constants.extend([Int(7), Value::Double(Double(10.0)), Bool(false)]);
+            constants
+        };
+        let plain = [element_of(constants()), not_element_of(constants())];
+        let mut bloomed = [element_of(constants()).with_bloom(10), not_element_of(constants()).with_bloom(10)];
+        for predicate in &mut bloomed {
+            predicate.push(Value::String(String::from("late")));
+        }
+
+        let mut values: Vec<Value> = (0..4_000).map(|i| Value::String(i.to_string())).collect();
+        values.extend([Int(7), Int(8), Value::Double(Double(10.00001)), Value::Double(Double(11.0)), Bool(false), Bool(true)]);
+        for value in &values {
+            for (plain, bloomed) in plain.iter().zip(&bloomed) {
+                assert_eq!(plain.evaluate(value), bloomed.evaluate(value), "{:?}", value);
+            }
+        }
+        assert!(bloomed[0].evaluate(&Value::String(String::from("late"))));
+        assert!(!bloomed[1].evaluate(&Value::String(String::from("late"))));
+    }
+
     #[test]
     fn not_equal_evaluation_for_not_the_same_value_is_not_correct(){
         let values = vec![
@@ -279,7 +921,6 @@ mod tests{
             // (Value::Bool(true), Value::Double(Double(10.0))), (Value::Bool(true), Value::String(String::from("10"))), (Value::Bool(true), Int(10)),
         ];
         for value in values {
-            println!("Testing {:?} and {:?}", &value.0, &value.1);
             assert_eq!(not_equal(value.0).evaluate(&value.1), true)
         }
     }
@@ -0,0 +1,70 @@
+//! Bloom filter placed in front of large `SetPredicate`s, so values that are
+//! certainly not in the set are rejected without scanning it.
+
+use alloc::vec;
+use alloc::vec::Vec;
+
+pub struct BloomFilter{
+    bits: Vec<u64>,
+    hashes: u32
+}
+
+impl BloomFilter{
+    /// Sizes the filter for `keys` keys at `bits_per_key` bits each, using
+    /// the number of hash functions that minimises false positives.
+    pub fn new(keys: usize, bits_per_key: usize) -> Self{
+        let bits = keys.max(1).saturating_mul(bits_per_key.max(1));
+        Self{
+            bits: vec![0; bits.div_ceil(64)],
+            hashes: ((bits_per_key * 69 + 50) / 100).clamp(1, 30) as u32
+        }
+    }
+
+    pub fn insert(&mut self, hash: u64){
+        let len = self.bits.len() as u64 * 64;
+        for bit in Self::probes(hash, self.hashes, len) {
+            self.bits[(bit / 64) as usize] |= 1 << (bit % 64);
+        }
+    }
+
+    /// False means the key was never inserted; true means it may have been.
+    pub fn might_contain(&self, hash: u64) -> bool{
+        let len = self.bits.len() as u64 * 64;
+        Self::probes(hash, self.hashes, len).all(|bit| self.bits[(bit / 64) as usize] & (1 << (bit % 64)) != 0)
+    }
+
+    fn probes(hash: u64, hashes: u32, len: u64) -> impl Iterator<Item = u64>{
+        // Ids come from FNV, whose low bits mix poorly; finish them with the
+        // splitmix64 finaliser before splitting into two probe hashes.
+        let mut h = hash;
+        h = (h ^ (h >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        h = (h ^ (h >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        h ^= h >> 31;
+        let (h1, h2) = (h & 0xffff_ffff, (h >> 32) | 1);
+        (0..u64::from(hashes)).map(move |i| h1.wrapping_add(i.wrapping_mul(h2)) % len)
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn no_false_negatives_and_few_false_positives(){
+        let mut bloom = BloomFilter::new(10_000, 10);
+        for key in 0..10_000u64 {
+            bloom.insert(key);
+        }
+        assert!((0..10_000u64).all(|key| bloom.might_contain(key)));
+
+        let false_positives = (10_000..110_000u64).filter(|key| bloom.might_contain(*key)).count();
+        assert!(false_positives < 2_000, "{} false positives in 100000", false_positives);
+    }
+
+    #[test]
+    fn empty_filter_contains_nothing(){
+        let bloom = BloomFilter::new(0, 8);
+        assert!(!(0..1_000u64).any(|key| bloom.might_contain(key)));
+    }
+}
@@ -0,0 +1,134 @@
+//! String collations for ordering predicates. Only strings are affected; every
+//! other value keeps its usual ordering.
+
+use crate::predicates::Value;
+use core::cmp::Ordering;
+
+#[derive(Debug, Default, Hash, Clone, Copy, PartialEq, Eq)]
+pub enum Collation{
+    /// Byte-wise ordering, so "Zebra" sorts before "apple".
+    #[default]
+    Binary,
+    /// ASCII letters compare without case; "apple" equals "APPLE".
+    CaseInsensitiveAscii,
+    /// Runs of ASCII digits compare as numbers, so "file10" sorts after "file2".
+    NumericAware
+}
+
+impl Collation{
+    pub fn compare(&self, left: &Value, right: &Value) -> Option<Ordering>{
+        match (self, left, right) {
+            (Collation::Binary, _, _) => left.partial_cmp(right),
+            (Collation::CaseInsensitiveAscii, Value::String(l), Value::String(r)) => {
+                Some(l.bytes().map(|b| b.to_ascii_lowercase()).cmp(r.bytes().map(|b| b.to_ascii_lowercase())))
+            }
+            (Collation::NumericAware, Value::String(l), Value::String(r)) => Some(numeric_aware(l.as_bytes(), r.as_bytes())),
+            _ => left.partial_cmp(right)
+        }
+    }
+}
+
+fn numeric_aware(mut left: &[u8], mut right: &[u8]) -> Ordering{
+    let (whole_left, whole_right) = (left, right);
+    loop {
+        let (l, r) = match (left.first(), right.first()) {
+            (None, None) => break,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(l), Some(r)) => (*l, *r)
+        };
+        if l.is_ascii_digit() && r.is_ascii_digit() {
+            let (l_run, l_rest) = split_digits(left);
+            let (r_run, r_rest) = split_digits(right);
+            // Without leading zeros a longer run is a larger number, and runs of
+            // the same length compare byte-wise.
+            let (l_num, r_num) = (trim_zeros(l_run), trim_zeros(r_run));
+            let ordering = l_num.len().cmp(&r_num.len()).then_with(|| l_num.cmp(r_num));
+            if ordering != Ordering::Equal {
+                return ordering;
+            }
+            left = l_rest;
+            right = r_rest;
+        } else {
+            if l != r {
+                return l.cmp(&r);
+            }
+            left = &left[1..];
+            right = &right[1..];
+        }
+    }
+    // "01" and "1" are the same number; keep the order total by falling back
+    // to the bytes.
+    whole_left.cmp(whole_right)
+}
+
+fn split_digits(bytes: &[u8]) -> (&[u8], &[u8]){
+    let end = bytes.iter().position(|b| !b.is_ascii_digit()).unwrap_or(bytes.len());
+    bytes.split_at(end)
+}
+
+fn trim_zeros(digits: &[u8]) -> &[u8]{
+    let start = digits.iter().position(|b| *b != b'0').unwrap_or(digits.len());
+    &digits[start..]
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::predicates::Value::Int;
+    use alloc::string::String;
+
+    fn s(value: &str) -> Value{
+        Value::String(String::from(value))
+    }
+
+    fn order(collation: Collation, left: &str, right: &str) -> Option<Ordering>{
+        collation.compare(&s(left), &s(right))
+    }
+
+    #[test]
+    fn binary_orders_by_bytes(){
+        assert_eq!(Some(Ordering::Less), order(Collation::Binary, "Zebra", "apple"));
+        assert_eq!(Some(Ordering::Less), order(Collation::Binary, "file10", "file2"));
+        assert_eq!(Some(Ordering::Equal), order(Collation::Binary, "a", "a"));
+    }
+
+    #[test]
+    fn case_insensitive_ascii_ignores_case(){
+        let c = Collation::CaseInsensitiveAscii;
+        assert_eq!(Some(Ordering::Greater), order(c, "Zebra", "apple"));
+        assert_eq!(Some(Ordering::Equal), order(c, "APPLE", "apple"));
+        assert_eq!(Some(Ordering::Less), order(c, "apple", "Apples"));
+        // Letters fold to lower case, so they sort after "[" like binary "a" does.
+        assert_eq!(Some(Ordering::Less), order(c, "[", "A"));
+        // Only ASCII folds.
+        assert_eq!(Some(Ordering::Greater), order(c, "ä", "Ä"));
+    }
+
+    #[test]
+    fn numeric_aware_compares_digit_runs_as_numbers(){
+        let c = Collation::NumericAware;
+        assert_eq!(Some(Ordering::Greater), order(c, "file10", "file2"));
+        assert_eq!(Some(Ordering::Less), order(c, "file2", "file10"));
+        assert_eq!(Some(Ordering::Less), order(c, "file2a", "file2b"));
+        assert_eq!(Some(Ordering::Less), order(c, "a9b", "a10a"));
+        assert_eq!(Some(Ordering::Less), order(c, "2", "10"));
+        assert_eq!(Some(Ordering::Less), order(c, "file", "file1"));
+        assert_eq!(Some(Ordering::Less), order(c, "x1", "xa"));
+        assert_eq!(Some(Ordering::Less), order(c, "99999999999999999999999", "100000000000000000000000"));
+        // Leading zeros only break ties.
+        assert_eq!(Some(Ordering::Less), order(c, "file002", "file10"));
+        assert_eq!(Some(Ordering::Less), order(c, "file01", "file1"));
+        assert_eq!(Some(Ordering::Equal), order(c, "file01", "file01"));
+        // Letters keep their byte order.
+        assert_eq!(Some(Ordering::Less), order(c, "Zebra", "apple"));
+    }
+
+    #[test]
+    fn non_strings_are_not_collated(){
+        for c in [Collation::Binary, Collation::CaseInsensitiveAscii, Collation::NumericAware] {
+            assert_eq!(Some(Ordering::Less), c.compare(&Int(2), &Int(10)));
+            assert_eq!(Int(2).partial_cmp(&s("a")), c.compare(&Int(2), &s("a")));
+        }
+    }
+}
@@ -1,7 +1,10 @@
-use std::ops::Not as OpsNot;
+use alloc::boxed::Box;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::ops::Not as OpsNot;
 use crate::predicates::{Predicate, Value};
 
-struct And
+pub struct And
 {
     lhs: Box<dyn Predicate>,
     rhs: Box<dyn Predicate>
@@ -69,7 +72,7 @@ impl Predicate for Ands
 }
 
 
-struct Or
+pub struct Or
 {
     lhs: Box<dyn Predicate>,
     rhs: Box<dyn Predicate>
@@ -130,7 +133,7 @@ impl Predicate for Ors {
     }
 }
 
-struct Not
+pub struct Not
 {
     pred: Box<dyn Predicate>,
 }
@@ -0,0 +1,54 @@
+//! Sharing primitives for the parts of the tree that are touched while
+//! matching. With the `std` feature these are thread-safe, so an `ATree` can
+//! be matched from several threads at once. Without it there are no threads
+//! to share with, and plain cells stand in for them.
+
+/// `Send + Sync` with the `std` feature, no requirement without it. Clocks and
+/// warning sinks need it because the tree that holds them may be matched from
+/// several threads.
+#[cfg(feature = "std")]
+pub trait ThreadSafe: Send + Sync {}
+
+#[cfg(feature = "std")]
+impl<T: Send + Sync + ?Sized> ThreadSafe for T {}
+
+#[cfg(not(feature = "std"))]
+pub trait ThreadSafe {}
+
+#[cfg(not(feature = "std"))]
+impl<T: ?Sized> ThreadSafe for T {}
+
+/// Interior mutability behind `&self`: a mutex with `std`, a `RefCell`
+/// without it.
+#[derive(Debug, Default)]
+pub struct Lock<T>(Inner<T>);
+
+#[cfg(feature = "std")]
+type Inner<T> = std::sync::Mutex<T>;
+
+#[cfg(not(feature = "std"))]
+type Inner<T> = core::cell::RefCell<T>;
+
+impl<T> Lock<T>{
+    pub fn new(value: T) -> Self{
+        Lock(Inner::new(value))
+    }
+
+    /// A panic while holding the lock can't leave anything half-updated that
+    /// a later caller would trip over, so poisoning is ignored.
+    #[cfg(feature = "std")]
+    pub fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R{
+        f(&mut self.0.lock().unwrap_or_else(std::sync::PoisonError::into_inner))
+    }
+
+    #[cfg(not(feature = "std"))]
+    pub fn with<R>(&self, f: impl FnOnce(&mut T) -> R) -> R{
+        f(&mut self.0.borrow_mut())
+    }
+}
+
+impl<T: Clone> Clone for Lock<T>{
+    fn clone(&self) -> Self {
+        Lock::new(self.with(|value| value.clone()))
+    }
+}
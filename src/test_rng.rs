@@ -0,0 +1,25 @@
+//! Xorshift generator for the randomized tests. Seeded by hand, so a failing
+//! run can be replayed. The Miri smoke test includes this file as well.
+
+#[derive(Clone)]
+pub struct XorShift(pub u64);
+
+impl XorShift{
+    pub fn next(&mut self) -> u64{
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    pub fn below(&mut self, n: u64) -> u64{
+        self.next() % n
+    }
+
+    pub fn shuffle<T>(&mut self, values: &mut [T]){
+        for i in (1..values.len()).rev() {
+            let j = self.below(i as u64 + 1) as usize;
+            values.swap(i, j);
+        }
+    }
+}
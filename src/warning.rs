@@ -0,0 +1,156 @@
+//! Recoverable anomalies seen while matching. The tree keeps going after each
+//! of them; a `WarningSink` set with `ATree::set_warning_sink` gets to see them.
+
+use crate::clock::Clock;
+use crate::sync::{Lock, ThreadSafe};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Warning{
+    /// A result arrived for a predicate no expression uses.
+    UnknownPredicate { id: u64 },
+    /// A result arrived for an id that belongs to an inner or root node.
+    /// `predicates::predicate_kind_of` tells whether the id was handed out
+    /// for a built-in predicate, i.e. whether a node's id collided with it.
+    NotALeaf { id: u64 },
+    /// A predicate got more than one result in the same event. `conflicting`
+    /// is set when the results disagree, in which case the leaf is unknown.
+    DuplicateResult { id: u64, conflicting: bool }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WarningKind{
+    UnknownPredicate,
+    NotALeaf,
+    DuplicateResult
+}
+
+const KINDS: usize = 3;
+
+impl Warning{
+    pub fn kind(&self) -> WarningKind{
+        match self {
+            Warning::UnknownPredicate { .. } => WarningKind::UnknownPredicate,
+            Warning::NotALeaf { .. } => WarningKind::NotALeaf,
+            Warning::DuplicateResult { .. } => WarningKind::DuplicateResult
+        }
+    }
+}
+
+pub trait WarningSink: ThreadSafe{
+    fn warn(&self, warning: Warning);
+}
+
+/// Keeps every warning in order; mostly useful in tests.
+#[derive(Debug, Default)]
+pub struct VecSink(Lock<Vec<Warning>>);
+
+impl VecSink{
+    pub fn new() -> Self{
+        Self::default()
+    }
+
+    pub fn take(&self) -> Vec<Warning>{
+        self.0.with(core::mem::take)
+    }
+}
+
+impl WarningSink for VecSink{
+    fn warn(&self, warning: Warning) {
+        self.0.with(|warnings| warnings.push(warning));
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct KindWindow{
+    second: i64,
+    passed: usize,
+    dropped: u64
+}
+
+/// Forwards at most `per_second` warnings of each kind per clock second and
+/// counts the rest.
+pub struct RateLimitedSink<S: WarningSink>{
+    inner: S,
+    per_second: usize,
+    clock: Arc<dyn Clock>,
+    windows: Lock<[KindWindow; KINDS]>
+}
+
+impl<S: WarningSink> RateLimitedSink<S>{
+    pub fn new(inner: S, per_second: usize, clock: Arc<dyn Clock>) -> Self{
+        Self{
+            inner,
+            per_second,
+            clock,
+            windows: Lock::new([KindWindow::default(); KINDS])
+        }
+    }
+
+    pub fn inner(&self) -> &S{
+        &self.inner
+    }
+
+    /// How many warnings of `kind` have been held back so far.
+    pub fn dropped(&self, kind: WarningKind) -> u64{
+        self.windows.with(|windows| windows[kind as usize].dropped)
+    }
+}
+
+impl<S: WarningSink> WarningSink for RateLimitedSink<S>{
+    fn warn(&self, warning: Warning) {
+        let second = self.clock.now_millis().div_euclid(1000);
+        let passed = self.windows.with(|windows| {
+            let window = &mut windows[warning.kind() as usize];
+            if window.second != second {
+                window.second = second;
+                window.passed = 0;
+            }
+            if window.passed >= self.per_second {
+                window.dropped += 1;
+                return false;
+            }
+            window.passed += 1;
+            true
+        });
+        if passed {
+            self.inner.warn(warning);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::clock::ManualClock;
+    use alloc::vec;
+
+    #[test]
+    fn rate_limiter_caps_each_kind_per_second(){
+        let clock = Arc::new(ManualClock::new(0));
+        let sink = RateLimitedSink::new(VecSink::new(), 2, clock.clone());
+
+        for id in 0..10 {
+            sink.warn(Warning::UnknownPredicate { id });
+        }
+        sink.warn(Warning::NotALeaf { id: 1 });
+        assert_eq!(
+            vec![Warning::UnknownPredicate { id: 0 }, Warning::UnknownPredicate { id: 1 }, Warning::NotALeaf { id: 1 }],
+            sink.inner().take()
+        );
+        assert_eq!(8, sink.dropped(WarningKind::UnknownPredicate));
+        assert_eq!(0, sink.dropped(WarningKind::NotALeaf));
+
+        clock.advance(999);
+        sink.warn(Warning::UnknownPredicate { id: 10 });
+        assert!(sink.inner().take().is_empty());
+
+        clock.advance(1);
+        for id in 11..14 {
+            sink.warn(Warning::UnknownPredicate { id });
+        }
+        assert_eq!(vec![Warning::UnknownPredicate { id: 11 }, Warning::UnknownPredicate { id: 12 }], sink.inner().take());
+        assert_eq!(10, sink.dropped(WarningKind::UnknownPredicate));
+    }
+}
@@ -0,0 +1,199 @@
+//! Binary encoding for batches of predicate results, so an external evaluator
+//! can hand its results straight to `ATree::matches_encoded`.
+//!
+//! Layout (version 1):
+//!
+//! ```text
+//! version: u8 (= 1)
+//! count:   LEB128 varint
+//! count x { id: u64 little-endian, result: u8 (0 = false, 1 = true, 2 = unknown) }
+//! ```
+
+use crate::PredResult;
+use alloc::vec::Vec;
+
+pub const VERSION: u8 = 1;
+
+const ENTRY_LEN: usize = 9;
+const FALSE: u8 = 0;
+const TRUE: u8 = 1;
+const UNKNOWN: u8 = 2;
+
+#[derive(Debug, PartialEq)]
+pub enum DecodeError{
+    Empty,
+    UnsupportedVersion(u8),
+    InvalidCount,
+    Truncated { expected: usize, actual: usize },
+    TrailingBytes(usize),
+    InvalidResult { index: usize, byte: u8 }
+}
+
+pub fn encode_results(results: &[PredResult], out: &mut Vec<u8>){
+    out.reserve(1 + 10 + results.len() * ENTRY_LEN);
+    out.push(VERSION);
+
+    let mut count = results.len() as u64;
+    loop {
+        let byte = (count & 0x7f) as u8;
+        count >>= 7;
+        if count == 0 {
+            out.push(byte);
+            break;
+        }
+        out.push(byte | 0x80);
+    }
+
+    for result in results {
+        out.extend_from_slice(&result.id.to_le_bytes());
+        out.push(match result.result {
+            Some(false) => FALSE,
+            Some(true) => TRUE,
+            None => UNKNOWN
+        });
+    }
+}
+
+pub fn decode_results(bytes: &[u8]) -> Result<Vec<PredResult>, DecodeError>{
+    Ok(decode_iter(bytes)?.collect())
+}
+
+/// Validates the whole batch up front and then yields the entries without
+/// allocating.
+pub(crate) fn decode_iter(bytes: &[u8]) -> Result<impl Iterator<Item = PredResult> + '_, DecodeError>{
+    let (&version, rest) = bytes.split_first().ok_or(DecodeError::Empty)?;
+    if version != VERSION {
+        return Err(DecodeError::UnsupportedVersion(version));
+    }
+
+    let (count, rest) = decode_count(rest)?;
+    let expected = count.checked_mul(ENTRY_LEN).ok_or(DecodeError::InvalidCount)?;
+    if rest.len() < expected {
+        return Err(DecodeError::Truncated { expected, actual: rest.len() });
+    }
+    if rest.len() > expected {
+        return Err(DecodeError::TrailingBytes(rest.len() - expected));
+    }
+
+    for (index, entry) in rest.chunks_exact(ENTRY_LEN).enumerate() {
+        let byte = entry[ENTRY_LEN - 1];
+        if byte > UNKNOWN {
+            return Err(DecodeError::InvalidResult { index, byte });
+        }
+    }
+
+    Ok(rest.chunks_exact(ENTRY_LEN).map(|entry| {
+        let mut id = [0; 8];
+        id.copy_from_slice(&entry[..8]);
+        PredResult{
+            id: u64::from_le_bytes(id),
+            result: match entry[8] {
+                FALSE => Some(false),
+                TRUE => Some(true),
+                _ => None
+            }
+        }
+    }))
+}
+
+fn decode_count(bytes: &[u8]) -> Result<(usize, &[u8]), DecodeError>{
+    let mut count: u64 = 0;
+    for (i, byte) in bytes.iter().enumerate() {
+        if i == 10 {
+            return Err(DecodeError::InvalidCount);
+        }
+        let bits = u64::from(byte & 0x7f);
+        count |= bits.checked_shl(7 * i as u32).filter(|v| v >> (7 * i as u32) == bits).ok_or(DecodeError::InvalidCount)?;
+        if byte & 0x80 == 0 {
+            if *byte == 0 && i > 0 {
+                // Overlong encodings are rejected so every batch has one encoding.
+                return Err(DecodeError::InvalidCount);
+            }
+            let count = usize::try_from(count).map_err(|_| DecodeError::InvalidCount)?;
+            return Ok((count, &bytes[i + 1..]));
+        }
+    }
+    Err(DecodeError::Truncated { expected: 1, actual: 0 })
+}
+
+#[cfg(test)]
+mod tests{
+    use super::*;
+    use crate::test_rng::XorShift;
+    use alloc::vec;
+
+    fn sample() -> Vec<PredResult>{
+        vec![
+            PredResult{ id: 0, result: Some(true) },
+            PredResult{ id: u64::MAX, result: Some(false) },
+            PredResult{ id: 0x0102_0304_0506_0708, result: None },
+        ]
+    }
+
+    #[test]
+    fn round_trip_is_exact(){
+        let mut bytes = vec![];
+        encode_results(&sample(), &mut bytes);
+
+        assert_eq!(1 + 1 + 3 * ENTRY_LEN, bytes.len());
+        assert_eq!(sample(), decode_results(&bytes).unwrap());
+    }
+
+    #[test]
+    fn round_trip_with_multi_byte_count(){
+        let results: Vec<PredResult> = (0..300).map(|id| PredResult{ id, result: Some(id % 2 == 0) }).collect();
+        let mut bytes = vec![];
+        encode_results(&results, &mut bytes);
+
+        assert_eq!(&[VERSION, 0xac, 0x02], &bytes[..3]);
+        assert_eq!(results, decode_results(&bytes).unwrap());
+    }
+
+    #[test]
+    fn empty_batch(){
+        let mut bytes = vec![];
+        encode_results(&[], &mut bytes);
+
+        assert_eq!(vec![VERSION, 0], bytes);
+        assert!(decode_results(&bytes).unwrap().is_empty());
+    }
+
+    #[test]
+    fn every_truncation_is_an_error(){
+        let mut bytes = vec![];
+        encode_results(&sample(), &mut bytes);
+
+        for len in 0..bytes.len() {
+            assert!(decode_results(&bytes[..len]).is_err(), "prefix of {} bytes decoded", len);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_input(){
+        assert_eq!(Err(DecodeError::Empty), decode_results(&[]));
+        assert_eq!(Err(DecodeError::UnsupportedVersion(7)), decode_results(&[7, 0]));
+        assert_eq!(Err(DecodeError::TrailingBytes(1)), decode_results(&[VERSION, 0, 0]));
+        assert_eq!(Err(DecodeError::InvalidCount), decode_results(&[VERSION, 0x80, 0x00]));
+        assert_eq!(Err(DecodeError::InvalidCount), decode_results(&[VERSION, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f]));
+        assert_eq!(Err(DecodeError::InvalidCount), decode_results(&[VERSION, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x80, 0x01]));
+        assert_eq!(
+            Err(DecodeError::InvalidResult { index: 0, byte: 3 }),
+            decode_results(&[VERSION, 1, 0, 0, 0, 0, 0, 0, 0, 0, 3])
+        );
+    }
+
+    #[test]
+    fn garbage_never_panics(){
+        let mut rng = XorShift(0x2545_F491_4F6C_DD1D);
+        for len in 0..64 {
+            for _ in 0..64 {
+                let bytes: Vec<u8> = (0..len).map(|_| rng.next() as u8).collect();
+                if let Ok(results) = decode_results(&bytes) {
+                    let mut encoded = vec![];
+                    encode_results(&results, &mut encoded);
+                    assert_eq!(bytes, encoded);
+                }
+            }
+        }
+    }
+}
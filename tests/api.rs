@@ -0,0 +1,252 @@
+//! Snapshot of the public API. Every entry below is checked against the crate
+//! when this file compiles, so a changed signature fails the build; the
+//! rendered list is compared with `api.txt`, so adding or dropping an entry
+//! without updating the snapshot fails the test. Regenerate the snapshot with
+//! `UPDATE_API=1 cargo test --test api`.
+//!
+//! Enum variants are checked by building them, so a variant that gains or
+//! loses a field fails as well.
+//!
+//! The registry is written by hand: items missing from it are not covered.
+
+#![cfg(feature = "std")]
+// The registry recurses once per entry.
+#![recursion_limit = "512"]
+
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use A_Tree::clock::{Clock, ManualClock};
+use A_Tree::postfix::{PostfixError, PostfixToken};
+use A_Tree::predicates::{CrossAttrPredicate, PredicateKind, PredicateKindTag};
+use A_Tree::warning::{RateLimitedSink, VecSink, Warning, WarningKind, WarningSink};
+use A_Tree::wire::DecodeError;
+use A_Tree::*;
+
+macro_rules! registry {
+    (@ $lines:ident) => {};
+    // Before the plain `fn` arm, which would take `impl` for a type.
+    (@ $lines:ident fn $owner:ident :: $name:ident : fn(&$receiver:ident) -> impl $bound:path; $($rest:tt)*) => {
+        let _: for<'a> fn(&'a $receiver) -> Box<dyn $bound + 'a> = |receiver| Box::new($owner::$name(receiver));
+        $lines.push(normalize(format!("fn {}::{}: fn(&{}) -> impl {}", stringify!($owner), stringify!($name), stringify!($receiver), stringify!($bound))));
+        registry!(@ $lines $($rest)*);
+    };
+    (@ $lines:ident fn $($path:ident)::+ : $ty:ty; $($rest:tt)*) => {
+        let _: $ty = $($path)::+;
+        $lines.push(normalize(format!("fn {}: {}", stringify!($($path)::+), stringify!($ty))));
+        registry!(@ $lines $($rest)*);
+    };
+    (@ $lines:ident field $owner:ident . $field:ident : $ty:ty; $($rest:tt)*) => {
+        let _: fn(&$owner) -> &$ty = |owner| &owner.$field;
+        $lines.push(normalize(format!("field {}.{}: {}", stringify!($owner), stringify!($field), stringify!($ty))));
+        registry!(@ $lines $($rest)*);
+    };
+    (@ $lines:ident variant $owner:ident :: $variant:ident; $($rest:tt)*) => {
+        if let $owner::$variant = $owner::$variant {}
+        $lines.push(normalize(format!("variant {}::{}", stringify!($owner), stringify!($variant))));
+        registry!(@ $lines $($rest)*);
+    };
+    (@ $lines:ident variant $owner:ident :: $variant:ident ( $($ty:ty),* ); $($rest:tt)*) => {
+        let _: fn($($ty),*) -> $owner = $owner::$variant;
+        $lines.push(normalize(format!("variant {}::{}({})", stringify!($owner), stringify!($variant), stringify!($($ty),*))));
+        registry!(@ $lines $($rest)*);
+    };
+    (@ $lines:ident variant $owner:ident :: $variant:ident { $($field:ident : $ty:ty),* }; $($rest:tt)*) => {
+        let _: fn($($ty),*) -> $owner = |$($field),*| $owner::$variant { $($field),* };
+        $lines.push(normalize(format!("variant {}::{} {{ {} }}", stringify!($owner), stringify!($variant), stringify!($($field: $ty),*))));
+        registry!(@ $lines $($rest)*);
+    };
+    ($($entries:tt)*) => {{
+        let mut lines: Vec<String> = vec![];
+        registry!(@ lines $($entries)*);
+        lines
+    }};
+}
+
+/// `stringify!` spaces paths and argument lists out and wraps long types;
+/// one line per entry keeps the snapshot diffable.
+fn normalize(line: String) -> String{
+    line.split_whitespace().collect::<Vec<_>>().join(" ").replace(" :: ", "::").replace(" ,", ",").replace(" :", ":")
+}
+
+fn public_api() -> Vec<String>{
+    registry! {
+        fn add_children: fn(&mut ArcNodeLink, &mut ArcNodeLink);
+        fn NodeType::new_leaf: fn(LeafNode) -> ArcNodeLink;
+        fn NodeType::new_inner: fn(InnerNode) -> ArcNodeLink;
+        fn NodeType::new_root: fn(RootNode) -> ArcNodeLink;
+        fn NodeType::get_level: fn(&NodeType) -> u32;
+        fn LeafNode::new: fn(u64) -> LeafNode;
+        fn InnerNode::new: fn(LogOperation) -> InnerNode;
+        fn InnerNode::and: fn() -> InnerNode;
+        fn InnerNode::or: fn() -> InnerNode;
+        fn InnerNode::not: fn() -> InnerNode;
+        fn InnerNode::at_least: fn(u32) -> InnerNode;
+        fn RootNode::new: fn(String, LogOperation) -> RootNode;
+        fn RootNode::and: fn(String) -> RootNode;
+        fn RootNode::or: fn(String) -> RootNode;
+        variant LogOperation::And;
+        variant LogOperation::Or;
+        variant LogOperation::Not;
+        variant LogOperation::AtLeast(u32);
+
+        fn PredResult::new: fn(u64, Option<bool>) -> PredResult;
+        field PredResult.id: u64;
+        field PredResult.result: Option<bool>;
+
+        fn ATree::new: fn() -> ATree;
+        fn ATree::insert: fn(&mut ATree, ArcNodeLink) -> Result<SubscriptionId, ATreeError>;
+        fn ATree::insert_wired: fn(&mut ATree, ArcNodeLink, &PredicateStore) -> Result<SubscriptionId, ATreeError>;
+        fn ATree::remove: fn(&mut ATree, &str) -> bool;
+        fn ATree::len: fn(&ATree) -> usize;
+        fn ATree::is_empty: fn(&ATree) -> bool;
+        fn ATree::get_m: fn(&ATree) -> u32;
+        fn ATree::deep_clone: fn(&ATree) -> ATree;
+        fn ATree::plan_insert: fn(&ATree, &[ArcNodeLink]) -> InsertPlan;
+        fn ATree::audit_unreferenced: fn(&ATree, &PredicateStore) -> AuditReport;
+        fn ATree::find_subsumed: fn(&ATree) -> Vec<(String, String)>;
+        fn ATree::set_sample_rate: fn(&mut ATree, &str, f32);
+        fn ATree::set_clock: fn(&mut ATree, Arc<dyn Clock>);
+        fn ATree::set_warning_sink: fn(&mut ATree, Arc<dyn WarningSink>);
+        fn ATree::set_deadline_check_interval: fn(&mut ATree, usize);
+        fn ATree::last_event_id: fn(&ATree) -> Option<u64>;
+        fn ATree::matches: fn(&ATree, &[PredResult]) -> HashSet<String>;
+        fn ATree::matched_subscriptions: fn(&ATree, &[PredResult]) -> Vec<SubscriptionId>;
+        fn ATree::match_with: fn(&ATree, &[PredResult], &MatchOptions) -> MatchOutcome;
+        fn ATree::matches_until: fn(&ATree, &[PredResult], i64) -> MatchOutcome;
+        fn ATree::matches_sampled: fn(&ATree, &[PredResult], u64) -> HashSet<String>;
+        fn ATree::matches_with_outcome: fn(&ATree, &[PredResult]) -> MatchOutcome;
+        fn ATree::matches_encoded: fn(&ATree, &[u8]) -> Result<HashSet<String>, DecodeError>;
+
+        variant ATreeError::CycleDetected { node_id: u64 };
+        variant ATreeError::MissingPredicate { predicate_id: u64 };
+        variant ATreeError::NotArity { node_id: u64, children: usize };
+
+        fn MatchOptions::deadline: fn(MatchOptions, i64) -> MatchOptions;
+        fn MatchOptions::sample_nonce: fn(MatchOptions, u64) -> MatchOptions;
+        fn MatchOptions::event_id: fn(MatchOptions, u64) -> MatchOptions;
+        fn MatchOptions::max_results: fn(MatchOptions, usize) -> MatchOptions;
+        fn MatchOptions::stop_on_limit: fn(MatchOptions, bool) -> MatchOptions;
+        fn MatchOptions::cancel_token: fn(MatchOptions, CancelToken) -> MatchOptions;
+        fn MatchOptions::collect_level_stats: fn(MatchOptions, bool) -> MatchOptions;
+        fn MatchOptions::strict: fn(MatchOptions, bool) -> MatchOptions;
+
+        fn CancelToken::new: fn() -> CancelToken;
+        fn CancelToken::cancel: fn(&CancelToken);
+        fn CancelToken::is_cancelled: fn(&CancelToken) -> bool;
+
+        field MatchOutcome.event_id: u64;
+        field MatchOutcome.matched: HashSet<String>;
+        field MatchOutcome.resolved_false: HashSet<String>;
+        field MatchOutcome.truncated: bool;
+        field MatchOutcome.cancelled: bool;
+        field MatchOutcome.skipped_unknowns: usize;
+        field MatchOutcome.unreferenced_results: usize;
+        field MatchOutcome.overflowed: bool;
+        field MatchOutcome.total_matched: usize;
+        field MatchOutcome.level_breakdown: Vec<LevelStats>;
+        field MatchOutcome.subscriptions: Vec<SubscriptionId>;
+        field MatchOutcome.resolved_false_subscriptions: Vec<SubscriptionId>;
+
+        field LevelStats.level: u32;
+        field LevelStats.enqueued: usize;
+        field LevelStats.evaluated: usize;
+        field LevelStats.resolved_true: usize;
+        field LevelStats.resolved_false: usize;
+        field LevelStats.unknown: usize;
+        field LevelStats.deliveries: usize;
+        field LevelStats.short_circuited: usize;
+
+        fn SubscriptionId::value: fn(&SubscriptionId) -> u64;
+
+        field InsertPlan.expressions: Vec<ExpressionPlan>;
+        field InsertPlan.would_create_nodes: usize;
+        field InsertPlan.would_share_nodes: usize;
+        field ExpressionPlan.would_create_nodes: usize;
+        field ExpressionPlan.would_share_nodes: usize;
+        field ExpressionPlan.validation_issues: Vec<ATreeError>;
+
+        field AuditReport.predicates_without_expressions: Vec<u64>;
+        field AuditReport.expressions_with_missing_predicates: Vec<String>;
+
+        fn Event::new: fn(Vec<EventValue>) -> Event;
+        fn Event::checked: fn(Vec<EventValue>) -> Result<Event, EventError>;
+        variant EventError::NonFinite { attribute: String };
+        field EventValue.name: String;
+        field EventValue.value: predicates::Value;
+
+        fn PredicateStore::new: fn() -> PredicateStore;
+        fn PredicateStore::add: fn(&mut PredicateStore, String, PredicateKind) -> Result<u64, PredicateError>;
+        fn PredicateStore::add_pattern: fn(&mut PredicateStore, &str, PredicateKind) -> Result<u64, PredicateError>;
+        fn PredicateStore::add_cross: fn(&mut PredicateStore, CrossAttrPredicate) -> Result<u64, PredicateError>;
+        fn PredicateStore::contains: fn(&PredicateStore, u64) -> bool;
+        fn PredicateStore::ids: fn(&PredicateStore) -> impl Iterator<Item = u64>;
+        fn PredicateStore::set_context: fn(&mut PredicateStore, Event);
+        fn PredicateStore::clear_context: fn(&mut PredicateStore);
+        fn PredicateStore::fallback: fn(&mut PredicateStore, &str, &[&str]);
+        fn PredicateStore::evaluate: fn(&PredicateStore, &Event) -> Vec<PredResult>;
+        variant PredicateError::AlreadyRegistered { other_attribute: String };
+        fn predicates::predicate_kind_of: fn(u64) -> Option<PredicateKindTag>;
+
+        fn wire::encode_results: fn(&[PredResult], &mut Vec<u8>);
+        fn wire::decode_results: fn(&[u8]) -> Result<Vec<PredResult>, DecodeError>;
+        variant DecodeError::Empty;
+        variant DecodeError::UnsupportedVersion(u8);
+        variant DecodeError::InvalidCount;
+        variant DecodeError::Truncated { expected: usize, actual: usize };
+        variant DecodeError::TrailingBytes(usize);
+        variant DecodeError::InvalidResult { index: usize, byte: u8 };
+
+        fn postfix::from_postfix: fn(&str, Vec<PostfixToken>, &mut PredicateStore) -> Result<ArcNodeLink, PostfixError>;
+        fn postfix::to_postfix: fn(&ArcNodeLink) -> Vec<PostfixToken>;
+        variant PostfixToken::Pred(u64);
+        variant PostfixToken::PredInline(PredicateKind, String);
+        variant PostfixToken::And(usize);
+        variant PostfixToken::Or(usize);
+        variant PostfixToken::Not;
+        variant PostfixToken::AtLeast(u32, usize);
+        variant PostfixError::Empty;
+        variant PostfixError::ZeroArity { position: usize };
+        variant PostfixError::StackUnderflow { position: usize, needed: usize, available: usize };
+        variant PostfixError::LeftoverOperands(usize);
+        variant PostfixError::Predicate { position: usize, error: PredicateError };
+
+        variant Warning::UnknownPredicate { id: u64 };
+        variant Warning::NotALeaf { id: u64 };
+        variant Warning::DuplicateResult { id: u64, conflicting: bool };
+        fn Warning::kind: fn(&Warning) -> WarningKind;
+        variant WarningKind::UnknownPredicate;
+        variant WarningKind::NotALeaf;
+        variant WarningKind::DuplicateResult;
+        fn VecSink::new: fn() -> VecSink;
+        fn VecSink::take: fn(&VecSink) -> Vec<Warning>;
+        fn RateLimitedSink::new: fn(VecSink, usize, Arc<dyn Clock>) -> RateLimitedSink<VecSink>;
+        fn RateLimitedSink::inner: fn(&RateLimitedSink<VecSink>) -> &VecSink;
+        fn RateLimitedSink::dropped: fn(&RateLimitedSink<VecSink>, WarningKind) -> u64;
+
+        fn ManualClock::new: fn(i64) -> ManualClock;
+        fn ManualClock::set: fn(&ManualClock, i64);
+        fn ManualClock::advance: fn(&ManualClock, i64);
+    }
+}
+
+#[test]
+fn public_api_matches_snapshot(){
+    let path = concat!(env!("CARGO_MANIFEST_DIR"), "/api.txt");
+    let actual = public_api().join("\n") + "\n";
+    if std::env::var_os("UPDATE_API").is_some() {
+        std::fs::write(path, &actual).unwrap();
+        return;
+    }
+
+    let expected = std::fs::read_to_string(path).unwrap_or_default();
+    let expected_lines: HashSet<&str> = expected.lines().collect();
+    let actual_lines: HashSet<&str> = actual.lines().collect();
+    let mut diff: Vec<String> = expected.lines().filter(|line| !actual_lines.contains(line)).map(|line| format!("- {}", line)).collect();
+    diff.extend(actual.lines().filter(|line| !expected_lines.contains(line)).map(|line| format!("+ {}", line)));
+    assert!(
+        diff.is_empty() && expected == actual,
+        "public API differs from api.txt (rerun with UPDATE_API=1 if intended):\n{}",
+        diff.join("\n")
+    );
+}
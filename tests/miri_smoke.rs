@@ -0,0 +1,82 @@
+//! Insert, match and remove cycles over random expressions, sized to finish
+//! under Miri (`cargo miri test --test miri_smoke`). Miri checks the
+//! `RefCell` borrows of the expressions walked by insert and the arena
+//! bookkeeping of remove. Without Miri the same cycles run more rounds.
+
+use A_Tree::*;
+use test_rng::XorShift;
+
+// The unit tests' generator; this test doesn't shuffle.
+#[allow(dead_code)]
+#[path = "../src/test_rng.rs"]
+mod test_rng;
+
+#[cfg(miri)]
+const ROUNDS: usize = 3;
+#[cfg(not(miri))]
+const ROUNDS: usize = 200;
+
+fn random_expression(rng: &mut XorShift, id: &str, leaf_ids: &[u64]) -> ArcNodeLink{
+    let mut root = if rng.below(2) == 0 {
+        NodeType::new_root(RootNode::and(id.to_string()))
+    } else {
+        NodeType::new_root(RootNode::or(id.to_string()))
+    };
+    for _ in 0..2 + rng.below(2) {
+        let mut child = if rng.below(2) == 0 {
+            let mut inner = NodeType::new_inner(if rng.below(2) == 0 { InnerNode::and() } else { InnerNode::or() });
+            for _ in 0..2 {
+                add_children(&mut inner, &mut NodeType::new_leaf(LeafNode::new(leaf_ids[rng.below(leaf_ids.len() as u64) as usize])));
+            }
+            inner
+        } else {
+            NodeType::new_leaf(LeafNode::new(leaf_ids[rng.below(leaf_ids.len() as u64) as usize]))
+        };
+        add_children(&mut root, &mut child);
+    }
+    root
+}
+
+#[test]
+fn insert_match_and_remove_cycles(){
+    let mut rng = XorShift(0x1234_5678_9ABC_DEF1);
+    for _ in 0..ROUNDS {
+        let leaf_ids: Vec<u64> = (0..4).map(|_| rng.next() | 1 << 40).collect();
+        let mut tree = ATree::new();
+        for expression in 0..5 {
+            tree.insert(random_expression(&mut rng, &expression.to_string(), &leaf_ids)).unwrap();
+        }
+        let len = tree.len();
+
+        // May be the same node as one inside the expressions above.
+        let mut root = NodeType::new_root(RootNode::or("again".to_string()));
+        for id in &leaf_ids[..2] {
+            add_children(&mut root, &mut NodeType::new_leaf(LeafNode::new(*id)));
+        }
+        tree.insert(root.clone()).unwrap();
+        tree.insert(root).unwrap();
+        // At most the root and its two leaves are new.
+        assert!(tree.len() <= len + 3);
+
+        let results: Vec<PredResult> = leaf_ids.iter()
+            .map(|id| PredResult::new(*id, Some(rng.below(2) == 0)))
+            .collect();
+        let first = tree.matches_with_outcome(&results);
+        assert_eq!(first, tree.match_with(&results, &MatchOptions::default().event_id(first.event_id)));
+        assert!(first.matched.is_disjoint(&first.resolved_false));
+
+        let removed = rng.below(5).to_string();
+        assert!(tree.remove(&removed));
+        assert!(!tree.remove(&removed));
+        let outcome = tree.matches_with_outcome(&results);
+        assert!(!outcome.matched.contains(&removed) && !outcome.resolved_false.contains(&removed));
+        assert_eq!(first.matched.contains("again"), outcome.matched.contains("again"));
+
+        for expression in 0..5 {
+            tree.remove(&expression.to_string());
+        }
+        assert!(tree.remove("again"));
+        assert!(tree.is_empty());
+        assert!(tree.matches(&results).is_empty());
+    }
+}
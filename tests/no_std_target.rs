@@ -0,0 +1,34 @@
+//! Builds the crate without `std` for a target that has no `std` at all, so a
+//! stray `std` path or an atomic the target lacks fails here rather than on
+//! the device. The host build with `--no-default-features` can't catch the
+//! latter. Needs `rustup target add thumbv7em-none-eabihf`; without the
+//! target the check is skipped with a note.
+
+use std::path::Path;
+use std::process::Command;
+
+const TARGET: &str = "thumbv7em-none-eabihf";
+
+fn target_installed() -> bool{
+    let libdir = Command::new("rustc").args(["--print", "target-libdir", "--target", TARGET]).output();
+    match libdir {
+        Ok(output) if output.status.success() => Path::new(String::from_utf8_lossy(&output.stdout).trim()).is_dir(),
+        _ => false
+    }
+}
+
+#[test]
+#[cfg_attr(miri, ignore)]
+fn builds_for_a_target_without_std(){
+    if !target_installed() {
+        eprintln!("skipping: {} is not installed", TARGET);
+        return;
+    }
+    let output = Command::new(env!("CARGO"))
+        .args(["build", "--lib", "--no-default-features", "--target", TARGET, "--target-dir"])
+        .arg(Path::new(env!("CARGO_TARGET_TMPDIR")).join("no_std"))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+}